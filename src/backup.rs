@@ -0,0 +1,91 @@
+//! Backup references written before sup touches anything, so that
+//! original HEAD, index and stashed changes stay reachable and can be
+//! recovered even if the stash is lost or reset goes wrong.
+
+use anyhow::Result;
+use git2::Repository;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+const BACKUP_REFS_PREFIX: &str = "refs/sup/backup";
+
+/// Backup of a single sup run, stored under refs/sup/backup/<timestamp>/
+pub(crate) struct Backup {
+    prefix: String,
+}
+
+impl Backup {
+    /// Record current HEAD and index, the latter as a commit on top of HEAD
+    /// like `git stash` does, since refs pointing to trees confuse fetch
+    pub(crate) fn create(repo: &Repository) -> Result<Self> {
+        let mut timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let mut prefix = format!("{BACKUP_REFS_PREFIX}/{timestamp}");
+        // several runs within the same millisecond should not overwrite each other
+        while repo
+            .references_glob(&format!("{prefix}/*"))?
+            .next()
+            .is_some()
+        {
+            timestamp += 1;
+            prefix = format!("{BACKUP_REFS_PREFIX}/{timestamp}");
+        }
+        let backup = Backup { prefix };
+
+        let head = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        match &head {
+            Some(head) => backup.write(repo, "head", head.id())?,
+            None => debug!("HEAD is unborn, not backing it up"),
+        }
+        let mut index = repo.index()?;
+        if index.has_conflicts() {
+            warn!("Index has conflicts, not backing it up");
+        } else {
+            let tree = repo.find_tree(index.write_tree()?)?;
+            let sig = repo.signature()?;
+            let parents: Vec<&git2::Commit> = head.iter().collect();
+            let commit = repo.commit(None, &sig, &sig, "sup backup of index", &tree, &parents)?;
+            backup.write(repo, "index", commit)?;
+        }
+        Ok(backup)
+    }
+
+    /// Record stash commit created for this run
+    pub(crate) fn record_stash(&self, repo: &Repository, stash_oid: &str) -> Result<()> {
+        self.write(repo, "stash", git2::Oid::from_str(stash_oid)?)
+    }
+
+    fn write(&self, repo: &Repository, name: &str, oid: git2::Oid) -> Result<()> {
+        let refname = format!("{}/{}", self.prefix, name);
+        debug!("Writing backup {} -> {}", refname, oid);
+        repo.reference(&refname, oid, true, "sup: backup before sync")?;
+        Ok(())
+    }
+}
+
+/// Remove all but the `keep` most recent backups
+pub(crate) fn prune(repo: &Repository, keep: usize) -> Result<()> {
+    let mut timestamps = Vec::new();
+    for reference in repo.references_glob(&format!("{BACKUP_REFS_PREFIX}/*"))? {
+        let reference = reference?;
+        let Some(name) = reference.name() else {
+            continue;
+        };
+        let timestamp = name
+            .strip_prefix(BACKUP_REFS_PREFIX)
+            .and_then(|rest| rest.trim_start_matches('/').split('/').next())
+            .and_then(|timestamp| timestamp.parse::<u128>().ok());
+        if let Some(timestamp) = timestamp {
+            if !timestamps.contains(&timestamp) {
+                timestamps.push(timestamp);
+            }
+        }
+    }
+    timestamps.sort_unstable_by(|a, b| b.cmp(a));
+    for timestamp in timestamps.into_iter().skip(keep) {
+        debug!("Removing old backup {}", timestamp);
+        for reference in repo.references_glob(&format!("{BACKUP_REFS_PREFIX}/{timestamp}/*"))? {
+            reference?.delete()?;
+        }
+    }
+    Ok(())
+}
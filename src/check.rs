@@ -0,0 +1,78 @@
+//! Project checks, like `cargo test`, run against the committed result
+//! including freshly pulled changes, before it is pushed.
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::process::{Command, Stdio};
+use tracing::{debug, instrument, Span};
+
+/// Returned when check command exits with non-zero code
+#[derive(Debug)]
+pub(crate) struct CheckFailedError {
+    pub(crate) command: String,
+    pub(crate) code: Option<i32>,
+}
+
+impl std::fmt::Display for CheckFailedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "Check {:?} failed with exit code {code}", self.command),
+            None => write!(f, "Check {:?} was terminated by a signal", self.command),
+        }?;
+        write!(
+            f,
+            ". Commit was not pushed: fix the problem, amend the commit or add another one, \
+            then run `sup continue` to check again and push, or `sup abort` to roll back"
+        )
+    }
+}
+
+impl std::error::Error for CheckFailedError {}
+
+/// Run check command with shell from the root of the working tree, showing its output
+#[instrument(skip_all)]
+pub(crate) fn run(repo: &git2::Repository, command: &str) -> Result<()> {
+    crate::ui::step(
+        &Span::current(),
+        "checking",
+        &format!("Running check {command}"),
+        "",
+    );
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(command);
+        c
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(command);
+        c
+    };
+    cmd.current_dir(repo.workdir().context("sup needs a working tree")?);
+    // show output as it comes, unless stdout is reserved for JSON events
+    if crate::output::is_json() {
+        cmd.stdout(std::io::stderr());
+    } else {
+        cmd.stdout(Stdio::inherit());
+    }
+    cmd.stderr(Stdio::inherit());
+    debug!("Running check: {}", command);
+    let status = tracing_indicatif::suspend_tracing_indicatif(|| cmd.status())
+        .with_context(|| format!("Failed to run check {command:?}"))?;
+    crate::output::event(
+        "check",
+        json!({ "command": command, "success": status.success(), "exit_code": status.code() }),
+    );
+    if !status.success() {
+        crate::ui::set_finish_message(&Span::current(), &format!("Check {command} failed"));
+        return Err(CheckFailedError {
+            command: command.to_string(),
+            code: status.code(),
+        }
+        .into());
+    }
+    crate::ui::set_finish_message(&Span::current(), &format!("Check {command} passed"));
+    Ok(())
+}
@@ -1,35 +1,333 @@
 use argh::FromArgs;
 
 /// sup - a tool for Trunk-Based Development to safely and quickly push code to git repository.
+/// Runs `sync` when no command is given, so that `sup` and `sup -m "message"` do the usual thing.
 #[derive(FromArgs, Debug)]
 pub struct Cli {
-    /// continue interrupted operation from where it left off
+    /// show version
+    #[argh(switch, short = 'v')]
+    pub version: bool,
+
+    /// output format: text (default), or json to write JSON Lines events and final result to stdout
+    #[argh(option, default = "crate::output::Format::Text")]
+    pub output: crate::output::Format,
+
+    /// show only errors
+    #[argh(switch, short = 'q')]
+    pub quiet: bool,
+
+    /// show debug logs
     #[argh(switch)]
-    pub r#continue: bool,
+    pub verbose: bool,
 
-    /// abort and rollback operation
+    /// log each step on its own line instead of showing progress bars, which is the default when not run in a terminal
     #[argh(switch)]
-    pub abort: bool,
+    pub no_progress: bool,
 
-    /// show version
-    #[argh(switch, short = 'v')]
-    pub version: bool,
+    #[argh(subcommand)]
+    pub command: Command,
+}
 
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub enum Command {
+    Sync(Sync),
+    Continue(Continue),
+    Abort(Abort),
+    Status(Status),
+    Undo(Undo),
+    Config(Config),
+}
+
+/// stash local changes, pull, bring changes back, and commit and push them when message is given
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "sync")]
+pub struct Sync {
     /// commit message for auto-commit after applying stash
     #[argh(option, short = 'm')]
     pub message: Option<String>,
 
-    /// skip confirmation prompt when removing stash after conflict
+    /// skip confirmation prompt when removing stash after conflict (sup.autoConfirmDropStash)
+    #[argh(switch, short = 'y')]
+    pub yes: bool,
+
+    /// no verify would skip pre-commit, commit-msg, pre-merge-commit and pre-push hooks if you are sure that your changes are ok (sup.noVerify)
+    #[argh(switch, short = 'n')]
+    pub no_verify: bool,
+
+    /// commit only staged changes, keeping unstaged ones in working tree (requires --message)
+    #[argh(switch)]
+    pub staged: bool,
+
+    /// choose files to commit with --message from a list of changed ones
+    #[argh(switch, short = 'i')]
+    pub interactive: bool,
+
+    /// rebase local commits onto upstream instead of merging (default is taken from sup.pullStrategy or pull.rebase git config)
+    #[argh(switch)]
+    pub rebase: bool,
+
+    /// refuse to pull when local branch has diverged and cannot be fast-forwarded (default is taken from sup.pullStrategy or pull.ff git config)
+    #[argh(switch)]
+    pub ff_only: bool,
+
+    /// fetch and show incoming and outgoing commits and whether local changes would conflict, without changing anything
+    #[argh(switch)]
+    pub dry_run: bool,
+
+    /// how many times to pull and retry push when it is rejected because remote has new commits (sup.pushRetries, 3 by default)
+    #[argh(option)]
+    pub push_retries: Option<u32>,
+
+    /// how many backups of HEAD, index and stash under refs/sup/backup to keep, 0 disables backups (sup.keepBackups, 10 by default)
+    #[argh(option)]
+    pub keep_backups: Option<usize>,
+
+    /// command to run after committing and before push, like "cargo test", leaving the commit unpushed when it fails (sup.checkCommand)
+    #[argh(option)]
+    pub check: Option<String>,
+
+    /// sign commits created by sup, as configured with gpg.format and user.signingKey (sup.gpgSign, default is taken from commit.gpgSign git config)
+    #[argh(switch)]
+    pub gpg_sign: bool,
+
+    /// do not sign commits created by sup, even if commit.gpgSign git config is set
+    #[argh(switch)]
+    pub no_gpg_sign: bool,
+
+    /// remove lock left by another sup process, even if it seems to be still running
+    #[argh(switch)]
+    pub force_unlock: bool,
+
+    /// paths to commit with --message, given after `--`, other changes are left in working tree
+    #[argh(positional)]
+    pub paths: Vec<String>,
+}
+
+/// continue interrupted operation from where it left off, after conflicts are resolved
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "continue")]
+pub struct Continue {
+    /// skip confirmation prompt when removing stash after conflict (sup.autoConfirmDropStash)
     #[argh(switch, short = 'y')]
     pub yes: bool,
 
-    /// no verify would skip pre-commit and pre-push hooks if you are sure that your changes are ok
+    /// skip pre-commit, commit-msg, pre-merge-commit and pre-push hooks (sup.noVerify)
+    #[argh(switch, short = 'n')]
+    pub no_verify: bool,
+
+    /// how many times to pull and retry push when it is rejected because remote has new commits (sup.pushRetries, 3 by default)
+    #[argh(option)]
+    pub push_retries: Option<u32>,
+
+    /// sign commits created by sup (sup.gpgSign, default is taken from commit.gpgSign git config)
+    #[argh(switch)]
+    pub gpg_sign: bool,
+
+    /// do not sign commits created by sup
+    #[argh(switch)]
+    pub no_gpg_sign: bool,
+
+    /// remove lock left by another sup process, even if it seems to be still running
+    #[argh(switch)]
+    pub force_unlock: bool,
+}
+
+/// abort interrupted operation and roll back to where it started, restoring local changes
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "abort")]
+pub struct Abort {
+    /// remove lock left by another sup process, even if it seems to be still running
+    #[argh(switch)]
+    pub force_unlock: bool,
+}
+
+/// roll back the last completed sup run: reset branch to where it was and restore local changes
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "undo")]
+pub struct Undo {
+    /// push commits reverting the pushed changes instead of rewriting remote branch
+    #[argh(switch)]
+    pub revert: bool,
+
+    /// reset remote branch to where it was before push, unless it was updated since
+    #[argh(switch)]
+    pub force_with_lease: bool,
+
+    /// skip pre-push hook
     #[argh(switch, short = 'n')]
     pub no_verify: bool,
 }
 
+/// show state of interrupted sup operation and what to run next
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "status")]
+pub struct Status {
+    /// print status as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// list effective settings from command line, SUP_* environment variables, .sup.toml and sup.* git config, and where each came from
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "config")]
+pub struct Config {
+    /// print settings as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+impl From<Sync> for crate::sup::RunOptions {
+    fn from(sync: Sync) -> Self {
+        crate::sup::RunOptions {
+            message: sync.message,
+            staged: sync.staged,
+            interactive: sync.interactive,
+            paths: sync.paths,
+            dry_run: sync.dry_run,
+            rebase: sync.rebase,
+            ff_only: sync.ff_only,
+            yes: sync.yes,
+            no_verify: sync.no_verify,
+            push_retries: sync.push_retries,
+            keep_backups: sync.keep_backups,
+            check: sync.check,
+            gpg_sign: sync.gpg_sign,
+            no_gpg_sign: sync.no_gpg_sign,
+            force_unlock: sync.force_unlock,
+        }
+    }
+}
+
+impl From<Continue> for crate::sup::RunOptions {
+    fn from(options: Continue) -> Self {
+        crate::sup::RunOptions {
+            yes: options.yes,
+            no_verify: options.no_verify,
+            push_retries: options.push_retries,
+            gpg_sign: options.gpg_sign,
+            no_gpg_sign: options.no_gpg_sign,
+            force_unlock: options.force_unlock,
+            ..Default::default()
+        }
+    }
+}
+
+/// Names of subcommands
+const COMMANDS: &[&str] = &[
+    "sync", "continue", "abort", "status", "undo", "config", "help",
+];
+
+/// Switches of sup itself, which can be given anywhere before `--`
+const GLOBAL_SWITCHES: &[&str] = &[
+    "-v",
+    "--version",
+    "-q",
+    "--quiet",
+    "--verbose",
+    "--no-progress",
+];
+
+/// Options of sup itself taking a value, which can be given anywhere before `--`
+const GLOBAL_OPTIONS: &[&str] = &["--output"];
+
+/// Options of subcommands taking a value, which should not be mistaken for a flag or command
+const COMMAND_OPTIONS: &[&str] = &[
+    "-m",
+    "--message",
+    "--push-retries",
+    "--keep-backups",
+    "--check",
+];
+
+/// Put options of sup itself before command, which is `sync` when not given,
+/// and turn `--continue` and `--abort` flags of older versions into commands.
+/// Command can follow switches of subcommand, like `sup -n status`, so that they are
+/// checked against that command instead of running sync.
+fn rewrite(args: &[String]) -> Result<Vec<String>, String> {
+    let mut global = Vec::new();
+    let mut rest = Vec::new();
+    let mut legacy = Vec::new();
+    // index in `rest` of the first argument which is neither a flag nor a value of option
+    let mut positional = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                rest.push(arg.clone());
+                rest.extend(args.by_ref().cloned());
+            }
+            "--continue" | "--abort" => legacy.push(arg.clone()),
+            arg if GLOBAL_SWITCHES.contains(&arg) => global.push(arg.to_string()),
+            arg if GLOBAL_OPTIONS.contains(&arg) => {
+                global.push(arg.to_string());
+                global.extend(args.next().cloned());
+            }
+            arg if COMMAND_OPTIONS.contains(&arg) => {
+                rest.push(arg.to_string());
+                rest.extend(args.next().cloned());
+            }
+            _ => {
+                if positional.is_none() && !arg.starts_with('-') {
+                    positional = Some(rest.len());
+                }
+                rest.push(arg.clone());
+            }
+        }
+    }
+    if let Some(index) = positional.filter(|index| COMMANDS.contains(&rest[*index].as_str())) {
+        let command = rest.remove(index);
+        rest.insert(0, command);
+    }
+
+    let given = rest.first().filter(|arg| COMMANDS.contains(&arg.as_str()));
+    let command = match (legacy.as_slice(), given) {
+        ([], Some(_)) => None,
+        ([], None) if matches!(rest.first().map(String::as_str), Some("--help" | "-h")) => None,
+        ([], None) => Some("sync".to_string()),
+        ([flag], None) => Some(flag.trim_start_matches('-').to_string()),
+        ([flag], Some(command)) => {
+            return Err(format!(
+                "{flag} cannot be used together with `{command}` command"
+            ))
+        }
+        _ => return Err("--continue and --abort cannot be used together".to_string()),
+    };
+    Ok(global.into_iter().chain(command).chain(rest).collect())
+}
+
 impl Cli {
     pub fn parse() -> Self {
-        argh::from_env()
+        let strings: Vec<String> = std::env::args_os()
+            .map(|s| s.into_string())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|arg| {
+                eprintln!("Invalid utf8: {}", arg.to_string_lossy());
+                std::process::exit(1)
+            });
+        let cmd = std::path::Path::new(strings.first().map_or("sup", String::as_str))
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("sup");
+        let args = rewrite(strings.get(1..).unwrap_or_default()).unwrap_or_else(|e| {
+            eprintln!("{e}\nRun {cmd} --help for more information.");
+            std::process::exit(1)
+        });
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Cli::from_args(&[cmd], &args).unwrap_or_else(|early_exit| {
+            std::process::exit(match early_exit.status {
+                Ok(()) => {
+                    println!("{}", early_exit.output);
+                    0
+                }
+                Err(()) => {
+                    eprintln!(
+                        "{}\nRun {cmd} --help for more information.",
+                        early_exit.output
+                    );
+                    1
+                }
+            })
+        })
     }
 }
@@ -0,0 +1,446 @@
+//! Settings of sup, which can be given (from highest to lowest precedence) as command line
+//! flags, `SUP_*` environment variables, `.sup.toml` in root of working tree or `sup.*` git config.
+
+use crate::pull::PullStrategy;
+use anyhow::{Context, Result};
+use git2::Repository;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Repository config file, in root of working tree
+const REPO_FILE: &str = ".sup.toml";
+
+/// Type of setting value, which decides how it is read from git config
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    /// boolean as git config understands it, where key without value is true
+    Bool,
+    /// can be given multiple times in git config, or as array in `.sup.toml`
+    List,
+}
+
+struct Key {
+    /// name in git config without `sup.` prefix
+    name: &'static str,
+    kind: Kind,
+    /// whether it can be set in `.sup.toml`, which is false for settings that run commands
+    /// or weaken checks, as anyone who lands a commit upstream controls that file
+    repo_file: bool,
+    default: Option<&'static str>,
+    /// what is used instead when setting has no value
+    unset: &'static str,
+}
+
+const KEYS: &[Key] = &[
+    Key {
+        name: "pullStrategy",
+        kind: Kind::Text,
+        repo_file: true,
+        default: None,
+        unset: "taken from pull.rebase and pull.ff git config",
+    },
+    Key {
+        name: "autoConfirmDropStash",
+        kind: Kind::Bool,
+        repo_file: true,
+        default: Some("false"),
+        unset: "",
+    },
+    Key {
+        name: "noVerify",
+        kind: Kind::Bool,
+        repo_file: false,
+        default: Some("false"),
+        unset: "",
+    },
+    Key {
+        name: "remote",
+        kind: Kind::Text,
+        repo_file: true,
+        default: None,
+        unset: "taken from upstream of current branch",
+    },
+    Key {
+        name: "pushRetries",
+        kind: Kind::Text,
+        repo_file: true,
+        default: Some("3"),
+        unset: "",
+    },
+    Key {
+        name: "keepBackups",
+        kind: Kind::Text,
+        repo_file: true,
+        default: Some("10"),
+        unset: "",
+    },
+    Key {
+        name: "pullWithCli",
+        kind: Kind::Bool,
+        repo_file: false,
+        default: Some("false"),
+        unset: "",
+    },
+    Key {
+        name: "gpgSign",
+        kind: Kind::Bool,
+        repo_file: false,
+        default: None,
+        unset: "taken from commit.gpgSign git config",
+    },
+    Key {
+        name: "checkCommand",
+        kind: Kind::Text,
+        repo_file: false,
+        default: None,
+        unset: "no check is run before push",
+    },
+    Key {
+        name: "watch",
+        kind: Kind::List,
+        repo_file: true,
+        default: None,
+        unset: "no paths are watched in pulled changes",
+    },
+];
+
+/// Where value of a setting came from, from lowest to highest precedence
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Source {
+    Default,
+    GitConfig,
+    RepoFile,
+    Env,
+    CommandLine,
+}
+
+/// Value of a setting, which is a list for settings given multiple times
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+enum Value {
+    One(String),
+    List(Vec<String>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::One(value) => write!(f, "{value}"),
+            Value::List(values) => write!(f, "{}", values.join(", ")),
+        }
+    }
+}
+
+/// Value of a setting and where it came from
+#[derive(Debug, Serialize)]
+struct Entry {
+    key: String,
+    value: Option<Value>,
+    source: Source,
+    /// environment variable or file the value was read from
+    origin: String,
+}
+
+impl Entry {
+    fn origin(&self) -> &str {
+        match self.source {
+            Source::Default => "default",
+            Source::GitConfig => "git config",
+            Source::CommandLine => "command line",
+            Source::RepoFile | Source::Env => &self.origin,
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = self.origin();
+        match self.value {
+            Some(ref value) => write!(f, "{} = {value} ({origin})", self.key),
+            None => write!(f, "{} is not set", self.key),
+        }
+    }
+}
+
+/// Effective settings of sup
+#[derive(Debug)]
+pub(crate) struct Config {
+    /// None to pick strategy from git config, as `git pull` does
+    pub(crate) pull_strategy: Option<PullStrategy>,
+    pub(crate) auto_confirm_drop_stash: bool,
+    pub(crate) no_verify: bool,
+    /// None to use upstream of current branch
+    pub(crate) remote: Option<String>,
+    pub(crate) push_retries: u32,
+    pub(crate) keep_backups: usize,
+    /// pull with `git pull` command instead of libgit2
+    pub(crate) pull_with_cli: bool,
+    /// whether to sign commits created by sup, None to follow `commit.gpgSign` git config
+    pub(crate) gpg_sign: Option<bool>,
+    /// shell command which has to succeed before commit is pushed
+    pub(crate) check_command: Option<String>,
+    /// pathspecs whose changes are pointed out in summary of pulled changes
+    pub(crate) watch: Vec<String>,
+    entries: Vec<Entry>,
+}
+
+impl Config {
+    /// Resolve settings, where `command_line` has values given as flags by setting name
+    pub(crate) fn load(repo: &Repository, command_line: &[(&str, String)]) -> Result<Self> {
+        let git_config = repo.config()?;
+        let file = read_repo_file(repo)?;
+        let mut entries = Vec::new();
+        for key in KEYS {
+            let env = env_var(key.name);
+            let mut entry = Entry {
+                key: format!("sup.{}", key.name),
+                value: key.default.map(|value| Value::One(value.to_string())),
+                source: Source::Default,
+                origin: String::new(),
+            };
+            let layers = [
+                (
+                    Source::GitConfig,
+                    git_config_value(&git_config, &entry.key, key.kind),
+                    String::new(),
+                ),
+                (
+                    Source::RepoFile,
+                    file.get(&file_key(key.name)).cloned(),
+                    REPO_FILE.to_string(),
+                ),
+                (Source::Env, env_value(&env, key.kind), env),
+                (
+                    Source::CommandLine,
+                    command_line
+                        .iter()
+                        .find(|(name, _)| *name == key.name)
+                        .map(|(_, value)| Value::One(value.clone())),
+                    String::new(),
+                ),
+            ];
+            for (source, value, origin) in layers {
+                if let Some(value) = value {
+                    entry.value = Some(value);
+                    entry.source = source;
+                    entry.origin = origin;
+                }
+            }
+            // PULL_WITH_CLI is still honored with any value, as it was before settings existed
+            if key.name == "pullWithCli"
+                && entry.source < Source::Env
+                && std::env::var_os("PULL_WITH_CLI").is_some()
+            {
+                entry.value = Some(Value::One("true".to_string()));
+                entry.source = Source::Env;
+                entry.origin = "PULL_WITH_CLI".to_string();
+            }
+            entries.push(entry);
+        }
+
+        Ok(Config {
+            pull_strategy: parse(&entries, "pullStrategy", |raw| raw.parse().ok())?,
+            auto_confirm_drop_stash: parse(&entries, "autoConfirmDropStash", parse_bool)?
+                .unwrap_or_default(),
+            no_verify: parse(&entries, "noVerify", parse_bool)?.unwrap_or_default(),
+            remote: parse(&entries, "remote", |raw| Some(raw.to_string()))?,
+            push_retries: parse(&entries, "pushRetries", |raw| raw.parse().ok())?
+                .unwrap_or_default(),
+            keep_backups: parse(&entries, "keepBackups", |raw| raw.parse().ok())?
+                .unwrap_or_default(),
+            pull_with_cli: parse(&entries, "pullWithCli", parse_bool)?.unwrap_or_default(),
+            gpg_sign: parse(&entries, "gpgSign", parse_bool)?,
+            check_command: parse(&entries, "checkCommand", |raw| {
+                Some(raw.to_string()).filter(|command| !command.trim().is_empty())
+            })?,
+            watch: match entries.iter().find(|entry| entry.key == "sup.watch") {
+                Some(Entry {
+                    value: Some(Value::List(paths)),
+                    ..
+                }) => paths.clone(),
+                _ => Vec::new(),
+            },
+            entries,
+        })
+    }
+}
+
+/// Typed value of setting, failing with where the value came from when it is invalid
+fn parse<T>(entries: &[Entry], name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>> {
+    let Some(entry) = entries
+        .iter()
+        .find(|entry| entry.key == format!("sup.{name}"))
+    else {
+        return Ok(None);
+    };
+    let Some(Value::One(ref raw)) = entry.value else {
+        return Ok(None);
+    };
+    match parse(raw) {
+        Some(value) => Ok(Some(value)),
+        None => anyhow::bail!(
+            "Invalid value {raw:?} of {} from {}",
+            entry.key,
+            entry.origin()
+        ),
+    }
+}
+
+/// Value of setting in git config, with booleans normalized as git reads them,
+/// so that key without value is true, and all values of a multi-value key
+fn git_config_value(config: &git2::Config, key: &str, kind: Kind) -> Option<Value> {
+    match kind {
+        Kind::List => {
+            let mut values = Vec::new();
+            config
+                .multivar(key, None)
+                .ok()?
+                .for_each(|entry| {
+                    if let Some(value) = entry.value() {
+                        values.push(value.to_string());
+                    }
+                })
+                .ok()?;
+            return (!values.is_empty()).then_some(Value::List(values));
+        }
+        Kind::Bool => {
+            if let Ok(value) = config.get_bool(key) {
+                return Some(Value::One(value.to_string()));
+            }
+        }
+        Kind::Text => {}
+    }
+    // invalid boolean is kept as it is, to be reported when parsed
+    config.get_string(key).ok().map(Value::One)
+}
+
+/// Value of setting in environment, where a list is separated like `PATH`
+fn env_value(name: &str, kind: Kind) -> Option<Value> {
+    match kind {
+        Kind::List => std::env::var_os(name).map(|value| {
+            Value::List(
+                std::env::split_paths(&value)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .filter(|path| !path.is_empty())
+                    .collect(),
+            )
+        }),
+        Kind::Text | Kind::Bool => std::env::var(name).ok().map(Value::One),
+    }
+}
+
+/// Boolean as git config understands it
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+/// `pushRetries` is `SUP_PUSH_RETRIES` in environment
+fn env_var(name: &str) -> String {
+    format!("SUP_{}", file_key(name).to_uppercase())
+}
+
+/// `pushRetries` is `push_retries` in `.sup.toml`
+fn file_key(name: &str) -> String {
+    let mut key = String::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            key.push('_');
+        }
+        key.push(c.to_ascii_lowercase());
+    }
+    key
+}
+
+/// Values from `.sup.toml` by key, empty when there is no such file
+fn read_repo_file(repo: &Repository) -> Result<BTreeMap<String, Value>> {
+    let Some(path) = repo.workdir().map(|workdir| workdir.join(REPO_FILE)) else {
+        return Ok(BTreeMap::new());
+    };
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = std::fs::read_to_string(&path)?;
+    let table: toml::Table = content
+        .parse()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let mut values = BTreeMap::new();
+    for (key, value) in table {
+        let Some(known) = KEYS.iter().find(|known| file_key(known.name) == key) else {
+            let known: Vec<String> = KEYS.iter().map(|known| file_key(known.name)).collect();
+            anyhow::bail!(
+                "Unknown setting {key:?} in {}, expected one of: {}",
+                path.display(),
+                known.join(", ")
+            );
+        };
+        if !known.repo_file {
+            tracing::warn!(
+                "Ignoring {key:?} in {}, it can only be set in git config, \
+                environment or command line",
+                path.display()
+            );
+            continue;
+        }
+        let value = match value {
+            toml::Value::String(value) if known.kind == Kind::List => Value::List(vec![value]),
+            toml::Value::Array(values) if known.kind == Kind::List => Value::List(
+                values
+                    .into_iter()
+                    .map(|value| match value {
+                        toml::Value::String(value) => Ok(value),
+                        other => anyhow::bail!(
+                            "Setting {key:?} in {} should be a list of strings, not contain {}",
+                            path.display(),
+                            other.type_str()
+                        ),
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            toml::Value::String(value) => Value::One(value),
+            toml::Value::Boolean(value) => Value::One(value.to_string()),
+            toml::Value::Integer(value) => Value::One(value.to_string()),
+            other => anyhow::bail!(
+                "Setting {key:?} in {} should be a string, boolean or number, not {}",
+                path.display(),
+                other.type_str()
+            ),
+        };
+        values.insert(key, value);
+    }
+    Ok(values)
+}
+
+/// `sup config`: list effective settings and where each of them came from
+pub fn run_config(json: bool) -> Result<()> {
+    let repo = crate::sup::open_repo()?;
+    let config = Config::load(&repo, &[])?;
+    if crate::output::is_json() {
+        // single line, so that output stays JSON Lines
+        println!("{}", serde_json::to_string(&config.entries)?);
+        return Ok(());
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&config.entries)?);
+        return Ok(());
+    }
+    for (entry, key) in config.entries.iter().zip(KEYS) {
+        let empty = match entry.value {
+            None => true,
+            Some(Value::List(ref values)) => values.is_empty(),
+            Some(Value::One(_)) => false,
+        };
+        if empty && !key.unset.is_empty() {
+            println!("{entry}, {}", key.unset);
+        } else {
+            println!("{entry}");
+        }
+    }
+    Ok(())
+}
@@ -1,8 +1,24 @@
 use std::path::{Path, PathBuf};
+use std::io::Write;
 use std::process::Command;
 use anyhow::Result;
 use tracing::debug;
 
+/// Returned when hook exits with non-zero code
+#[derive(Debug)]
+pub(crate) struct HookFailedError {
+    pub(crate) hook: PathBuf,
+    pub(crate) code: Option<i32>,
+}
+
+impl std::fmt::Display for HookFailedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hook {:?} failed with exit code {:?}", self.hook, self.code)
+    }
+}
+
+impl std::error::Error for HookFailedError {}
+
 /// Get the hooks directory for the repository, respecting core.hooksPath if set.
 pub fn get_hooks_dir(repo: &git2::Repository) -> Result<PathBuf> {
     // Try to get core.hooksPath from config
@@ -12,17 +28,79 @@ pub fn get_hooks_dir(repo: &git2::Repository) -> Result<PathBuf> {
         if hooks_path.is_absolute() {
             Ok(hooks_path)
         } else {
-            // Relative to repo root
-            Ok(repo.path().parent().unwrap_or_else(|| Path::new(".")).join(hooks_path))
+            // Relative to root of the working tree, where git runs hooks
+            Ok(working_dir(repo).join(hooks_path))
         }
     } else {
-        // Default to .git/hooks
-        Ok(repo.path().join("hooks"))
+        // Default to .git/hooks, shared by all linked worktrees
+        Ok(repo.commondir().join("hooks"))
     }
 }
 
+/// Root of the working tree, falling back to git directory for bare repositories
+fn working_dir(repo: &git2::Repository) -> &Path {
+    repo.workdir().unwrap_or_else(|| repo.path())
+}
+
 /// Run a hook script if it exists and is executable. Returns Ok(true) if run, Ok(false) if not present.
 pub fn run_hook(repo: &git2::Repository, hook_name: &str, args: &[&str]) -> Result<bool> {
+    run_hook_with_env(repo, hook_name, args, &[])
+}
+
+/// Run a hook which cannot affect the outcome, like `post-commit`, only warning when it fails
+pub(crate) fn run_post_hook(repo: &git2::Repository, hook_name: &str, args: &[&str]) {
+    if let Err(e) = run_hook(repo, hook_name, args) {
+        tracing::warn!("{} hook failed: {}", hook_name, e);
+    }
+}
+
+/// Author identity git exports to hooks run while committing
+pub(crate) fn author_env(author: &git2::Signature) -> Vec<(&'static str, String)> {
+    let when = author.when();
+    let offset = when.offset_minutes();
+    vec![
+        ("GIT_AUTHOR_NAME", author.name().unwrap_or_default().to_string()),
+        ("GIT_AUTHOR_EMAIL", author.email().unwrap_or_default().to_string()),
+        (
+            "GIT_AUTHOR_DATE",
+            format!(
+                "@{} {}{:02}{:02}",
+                when.seconds(),
+                if offset < 0 { '-' } else { '+' },
+                offset.abs() / 60,
+                offset.abs() % 60
+            ),
+        ),
+    ]
+}
+
+/// Run a hook with extra environment variables on top of the ones git always sets
+pub(crate) fn run_hook_with_env(
+    repo: &git2::Repository,
+    hook_name: &str,
+    args: &[&str],
+    env: &[(&str, String)],
+) -> Result<bool> {
+    run(repo, hook_name, args, env, None)
+}
+
+/// Run a hook feeding `input` to its stdin, like ref lines given to `pre-push`
+pub(crate) fn run_hook_with_input(
+    repo: &git2::Repository,
+    hook_name: &str,
+    args: &[&str],
+    input: &[u8],
+) -> Result<bool> {
+    run(repo, hook_name, args, &[], Some(input))
+}
+
+/// Whether hook is present, so that its input is only prepared when it will be run
+pub(crate) fn exists(repo: &git2::Repository, hook_name: &str) -> Result<bool> {
+    Ok(find(repo, hook_name)?.is_some())
+}
+
+/// Path of hook script, None when there is no such hook
+fn find(repo: &git2::Repository, hook_name: &str) -> Result<Option<PathBuf>> {
     debug!("Looking for hook: {}", hook_name);
     let hooks_dir = get_hooks_dir(repo)?;
     let hook_path = hooks_dir.join(hook_name);
@@ -37,8 +115,17 @@ pub fn run_hook(repo: &git2::Repository, hook_name: &str, args: &[&str]) -> Resu
     #[cfg(not(windows))]
     let candidates = [hook_path.clone()];
     debug!("Hook candidates: {:?}", candidates);
-    let hook = candidates.iter().find(|p| p.exists());
-    if let Some(hook) = hook {
+    Ok(candidates.into_iter().find(|p| p.exists()))
+}
+
+fn run(
+    repo: &git2::Repository,
+    hook_name: &str,
+    args: &[&str],
+    env: &[(&str, String)],
+    input: Option<&[u8]>,
+) -> Result<bool> {
+    if let Some(ref hook) = find(repo, hook_name)? {
         debug!("Running hook: {}", hook.display());
         use std::process::Stdio;
         #[cfg(windows)]
@@ -65,13 +152,38 @@ pub fn run_hook(repo: &git2::Repository, hook_name: &str, args: &[&str]) -> Resu
             c.args(args);
             c
         };
-        // Set environment variables as git does
-        // TODO: Add more env vars if needed
+        // Hooks are run from the root of the working tree, as git does
+        cmd.current_dir(working_dir(repo));
+        // Set environment variables as git does, so that git commands in hooks
+        // work on this repository even from linked worktrees or submodules
+        cmd.env("GIT_DIR", repo.path());
+        cmd.env("GIT_INDEX_FILE", repo.path().join("index"));
+        // sup never opens an editor, same as `git commit -m`
+        cmd.env("GIT_EDITOR", ":");
+        cmd.envs(env.iter().map(|(key, value)| (key, value)));
 
-        // inherit stdout and stderr
-        cmd.stdout(Stdio::inherit());
+        // inherit stdout and stderr, unless stdout is reserved for JSON events
+        if crate::output::is_json() {
+            cmd.stdout(std::io::stderr());
+        } else {
+            cmd.stdout(Stdio::inherit());
+        }
         cmd.stderr(Stdio::inherit());
-        let status_result = tracing_indicatif::suspend_tracing_indicatif(|| cmd.status());
+        let status_result = tracing_indicatif::suspend_tracing_indicatif(|| match input {
+            Some(input) => {
+                cmd.stdin(Stdio::piped());
+                let mut child = cmd.spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    match stdin.write_all(input) {
+                        // hook does not have to read its input, as with git
+                        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+                        result => result?,
+                    }
+                }
+                child.wait()
+            }
+            None => cmd.status(),
+        });
         match status_result {
             Ok(status) => {
                 if !status.success() {
@@ -80,7 +192,11 @@ pub fn run_hook(repo: &git2::Repository, hook_name: &str, args: &[&str]) -> Resu
                     debug!("Args: {:?}", args);
                     debug!("Exit code: {:?}", status.code());
                     debug!("--- END HOOK DEBUG ---\n");
-                    anyhow::bail!("Hook {:?} failed with exit code {:?}", hook, status.code());
+                    return Err(HookFailedError {
+                        hook: hook.clone(),
+                        code: status.code(),
+                    }
+                    .into());
                 }
                 Ok(true)
             }
@@ -0,0 +1,111 @@
+//! Journal of the last sup run, recording everything needed to roll it back
+//! with `sup undo` after it has completed.
+
+use crate::sup::git_file;
+use anyhow::{Context, Result};
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use tracing::debug;
+
+const JOURNAL_FILE: &str = "sup_journal";
+
+/// Reference updated on remote by push
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub(crate) struct PushedRef {
+    pub(crate) remote: String,
+    /// full name of reference on remote, like refs/heads/main
+    pub(crate) refname: String,
+    /// what remote reference pointed to before push, None if it was created
+    pub(crate) old_oid: Option<String>,
+    pub(crate) new_oid: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub(crate) struct Journal {
+    /// full name of the branch sup was run on, None for detached HEAD
+    pub(crate) branch: Option<String>,
+    pub(crate) original_head: String,
+    pub(crate) stash_oid: Option<String>,
+    /// commits created from stashed changes
+    pub(crate) commits: Vec<String>,
+    pub(crate) pushed: Option<PushedRef>,
+    /// HEAD after the run has completed, None while it is still running
+    pub(crate) head_after: Option<String>,
+}
+
+impl Journal {
+    /// Start a new journal for the run, replacing the one of previous run
+    pub(crate) fn start(repo: &Repository, stash_oid: Option<&str>) -> Result<()> {
+        let head = repo.head()?;
+        let original_head = head
+            .target()
+            .context("Cannot determine original head for journal")?
+            .to_string();
+        let branch = if head.is_branch() {
+            head.name().map(str::to_string)
+        } else {
+            None
+        };
+        Journal {
+            branch,
+            original_head,
+            stash_oid: stash_oid.map(str::to_string),
+            commits: Vec::new(),
+            pushed: None,
+            head_after: None,
+        }
+        .save(repo)
+    }
+
+    pub(crate) fn load(repo: &Repository) -> Result<Option<Self>> {
+        let path = git_file(repo, JOURNAL_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let buf = fs::read_to_string(path)?;
+        let journal = serde_json::from_str(&buf).context("Failed to parse sup journal")?;
+        Ok(Some(journal))
+    }
+
+    fn save(&self, repo: &Repository) -> Result<()> {
+        fs::write(git_file(repo, JOURNAL_FILE), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn clear(repo: &Repository) -> Result<()> {
+        let path = git_file(repo, JOURNAL_FILE);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Update journal of the current run, if there is one
+    fn update(repo: &Repository, f: impl FnOnce(&mut Journal)) -> Result<()> {
+        match Journal::load(repo)? {
+            Some(mut journal) => {
+                f(&mut journal);
+                journal.save(repo)
+            }
+            None => {
+                debug!("No sup journal to update");
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn record_commit(repo: &Repository, oid: git2::Oid) -> Result<()> {
+        Journal::update(repo, |journal| journal.commits.push(oid.to_string()))
+    }
+
+    pub(crate) fn record_push(repo: &Repository, pushed: PushedRef) -> Result<()> {
+        Journal::update(repo, |journal| journal.pushed = Some(pushed))
+    }
+
+    /// Mark the run as completed, so that it can be undone
+    pub(crate) fn finish(repo: &Repository) -> Result<()> {
+        let head_after = repo.head()?.target().map(|oid| oid.to_string());
+        Journal::update(repo, |journal| journal.head_after = head_after)
+    }
+}
@@ -0,0 +1,241 @@
+//! Lock file preventing concurrent sup runs in the same worktree. It records
+//! which process holds it, so that locks left behind by crashed runs can be told apart.
+
+use crate::sup::git_file;
+use anyhow::{Context, Result};
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+pub(crate) const LOCK_FILE: &str = "sup.lock";
+
+/// Process holding the lock
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LockHolder {
+    pub(crate) pid: u32,
+    pub(crate) hostname: String,
+    /// seconds since unix epoch
+    pub(crate) started_at: u64,
+    pub(crate) command: String,
+}
+
+impl LockHolder {
+    fn current() -> Self {
+        LockHolder {
+            pid: std::process::id(),
+            hostname: hostname(),
+            started_at: now(),
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    /// Whether holder could still be running. Processes on other hosts
+    /// (sharing repository over network drive) cannot be checked and are assumed alive.
+    pub(crate) fn is_alive(&self) -> bool {
+        if self.hostname != hostname() {
+            return true;
+        }
+        process_alive(self.pid)
+    }
+}
+
+impl std::fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let elapsed = now().saturating_sub(self.started_at);
+        let elapsed = match elapsed {
+            0..60 => format!("{elapsed}s"),
+            60..3600 => format!("{}m", elapsed / 60),
+            _ => format!("{}h", elapsed / 3600),
+        };
+        write!(
+            f,
+            "process {} on {}, started {} ago: {}",
+            self.pid, self.hostname, elapsed, self.command
+        )
+    }
+}
+
+/// Returned when lock is held by another sup process
+#[derive(Debug)]
+pub(crate) struct LockHeldError {
+    pub(crate) path: PathBuf,
+    /// None when lock file could not be read, for example if written by older sup version
+    pub(crate) holder: Option<LockHolder>,
+    pub(crate) alive: bool,
+}
+
+impl std::fmt::Display for LockHeldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.holder {
+            Some(ref holder) if self.alive => write!(
+                f,
+                "Another sup process is running ({holder}), lock {} is taken. Aborting.",
+                self.path.display()
+            ),
+            Some(ref holder) => write!(
+                f,
+                "Lock {} was left by {holder}, which is not running anymore. \
+                Run with --force-unlock to remove it.",
+                self.path.display()
+            ),
+            None => write!(
+                f,
+                "Another sup process could be running, lock {} is taken. \
+                If it is not, run with --force-unlock to remove the lock.",
+                self.path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LockHeldError {}
+
+/// Removes lock file when dropped, even on panic
+pub(crate) struct LockGuard {
+    path: PathBuf,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Read who holds the lock, if anyone
+pub(crate) fn holder(repo: &Repository) -> Result<Option<LockHolder>> {
+    let path = git_file(repo, LOCK_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&path)?;
+    match serde_json::from_str(&content) {
+        Ok(holder) => Ok(Some(holder)),
+        Err(e) => {
+            debug!("Cannot parse lock file {}: {}", path.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+/// Acquire lock file to prevent concurrent sup runs. Lock left by a process
+/// which is not running anymore can be removed after confirmation,
+/// while `force_unlock` removes any lock.
+pub(crate) fn lock(repo: &Repository, force_unlock: bool) -> Result<LockGuard> {
+    let lock_path = git_file(repo, LOCK_FILE);
+    let mut attempts = 0;
+    let mut file = loop {
+        attempts += 1;
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(file) => break file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempts == 1 => {
+                let holder = holder(repo)?;
+                let alive = holder.as_ref().is_none_or(LockHolder::is_alive);
+                let held = LockHeldError {
+                    path: lock_path.clone(),
+                    holder,
+                    alive,
+                };
+                if force_unlock {
+                    warn!("Removing lock: {}", held);
+                } else if !alive && confirm_removing_stale_lock(&held)? {
+                    debug!("Removing stale lock {}", lock_path.display());
+                } else {
+                    return Err(held.into());
+                }
+                fs::remove_file(&lock_path)
+                    .with_context(|| format!("Failed to remove lock {}", lock_path.display()))?;
+            }
+            Err(e) => {
+                let holder = holder(repo).ok().flatten();
+                if e.kind() == std::io::ErrorKind::AlreadyExists {
+                    // someone else took the lock right after stale one was removed
+                    return Err(LockHeldError {
+                        path: lock_path,
+                        holder,
+                        alive: true,
+                    }
+                    .into());
+                }
+                anyhow::bail!("Could not take a lock {}: {}", lock_path.display(), e);
+            }
+        }
+    };
+    let lock_guard = LockGuard {
+        path: lock_path.clone(),
+    };
+    file.write_all(serde_json::to_string(&LockHolder::current())?.as_bytes())?;
+
+    ctrlc::set_handler(move || {
+        let _ = fs::remove_file(&lock_path);
+        exit(1)
+    })?;
+    Ok(lock_guard)
+}
+
+fn confirm_removing_stale_lock(held: &LockHeldError) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let remove = dialoguer::Confirm::new()
+        .with_prompt(format!("{held} Remove it now?"))
+        .default(true)
+        .interact()?;
+    Ok(remove)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: buffer is valid for writes of its length
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if res != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(windows)]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 is not sent, only checks whether process exists
+    // SAFETY: kill has no memory safety requirements
+    let res = unsafe { libc::kill(pid, 0) };
+    // EPERM means process exists, but belongs to another user
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    let output = std::process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {pid}"), "/NH", "/FO", "CSV"])
+        .output();
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).contains(&format!("\"{pid}\"")),
+        // cannot tell, so better not to break the lock
+        Err(_) => true,
+    }
+}
@@ -1,15 +1,59 @@
 mod ui;
+mod backup;
+mod check;
 mod cli;
+mod config;
 mod credentials;
 mod hooks;
+mod journal;
+mod lock;
+mod message;
+mod output;
+mod preview;
 mod pull;
+mod select;
 mod sup;
 mod serde;
+mod sign;
+mod status;
+mod summary;
+mod undo;
+mod upstream;
 
 fn main() {
     let cli = cli::Cli::parse();
-    if let Err(e) = sup::run_sup(cli.r#continue, cli.abort, cli.version, cli.message, cli.yes, cli.no_verify) {
-        println!("Error: {e}");
-        std::process::exit(1);
+    if cli.version {
+        println!("sup version {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, true) => {
+            let error = anyhow::anyhow!("--quiet and --verbose cannot be used together");
+            if cli.output == output::Format::Json {
+                // JSON output still ends with a result event
+                output::init(cli.output, output::Verbosity::Normal, cli.no_progress);
+                std::process::exit(output::report(&Err(error)));
+            }
+            eprintln!("Error: {error}");
+            std::process::exit(output::EXIT_ERROR);
+        }
+        (true, false) => output::Verbosity::Quiet,
+        (false, true) => output::Verbosity::Verbose,
+        (false, false) => output::Verbosity::Normal,
+    };
+    output::init(cli.output, verbosity, cli.no_progress);
+    let result = match cli.command {
+        cli::Command::Sync(sync) => sup::run_sync(sync.into()),
+        cli::Command::Continue(options) => sup::run_continue(options.into()),
+        cli::Command::Abort(abort) => sup::run_abort(abort.force_unlock),
+        cli::Command::Status(status) => status::run_status(status.json),
+        cli::Command::Undo(undo) => {
+            undo::run_undo(undo.revert, undo.force_with_lease, undo.no_verify)
+        }
+        cli::Command::Config(config) => config::run_config(config.json),
+    };
+    let exit_code = output::report(&result);
+    if exit_code != 0 {
+        std::process::exit(exit_code);
     }
 }
@@ -0,0 +1,76 @@
+//! Commit message as git would commit it: read back after hooks have had a chance
+//! to edit it, cleaned up according to `commit.cleanup`, and refused when empty.
+
+use anyhow::{Context, Result};
+use git2::Repository;
+use std::path::Path;
+
+/// How commit message is cleaned up, as set by `commit.cleanup` git config
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cleanup {
+    /// strip comment lines and whitespace
+    Strip,
+    /// strip leading and trailing empty lines, trailing whitespace and repeated empty lines
+    Whitespace,
+    /// keep message as it is
+    Verbatim,
+}
+
+/// Read message file left by `prepare-commit-msg` and `commit-msg` hooks and clean it up
+pub(crate) fn read(repo: &Repository, path: &Path) -> Result<String> {
+    let message = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read commit message from {}", path.display()))?;
+    let config = repo.config()?;
+    let cleanup = match config.get_string("commit.cleanup").ok().as_deref() {
+        // message given with -m is not edited, so default and scissors leave comments in
+        None | Some("default" | "scissors" | "whitespace") => Cleanup::Whitespace,
+        Some("strip") => Cleanup::Strip,
+        Some("verbatim") => Cleanup::Verbatim,
+        Some(other) => anyhow::bail!("Invalid commit.cleanup mode {other:?}"),
+    };
+    let comment = match config.get_string("core.commentChar").ok().as_deref() {
+        None | Some("") => "#".to_string(),
+        Some("auto") => auto_comment_char(&message),
+        Some(comment) => comment.to_string(),
+    };
+    let message = cleanup_message(&message, cleanup, &comment);
+    if message.trim().is_empty() {
+        anyhow::bail!("Aborting commit due to empty commit message");
+    }
+    Ok(message)
+}
+
+fn cleanup_message(message: &str, cleanup: Cleanup, comment: &str) -> String {
+    if cleanup == Cleanup::Verbatim {
+        return message.to_string();
+    }
+    let mut cleaned = String::new();
+    let mut empty_lines = 0;
+    for line in message.lines() {
+        if cleanup == Cleanup::Strip && line.starts_with(comment) {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            empty_lines += 1;
+            continue;
+        }
+        // leading empty lines are dropped, and repeated ones collapsed into one
+        if empty_lines > 0 && !cleaned.is_empty() {
+            cleaned.push('\n');
+        }
+        empty_lines = 0;
+        cleaned.push_str(line);
+        cleaned.push('\n');
+    }
+    cleaned
+}
+
+/// First of the characters git tries for `core.commentChar=auto` which no line starts with
+fn auto_comment_char(message: &str) -> String {
+    let candidate = "#;@!$%^&|:"
+        .chars()
+        .find(|c| !message.lines().any(|line| line.starts_with(*c)))
+        .unwrap_or('#');
+    candidate.to_string()
+}
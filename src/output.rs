@@ -0,0 +1,188 @@
+//! Output format of sup: text for people, with progress bars when run in a terminal
+//! and line by line otherwise, or JSON Lines events for tools wrapping sup.
+//! Failures are told apart by exit code in all formats.
+
+use serde_json::{json, Map, Value};
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    /// one JSON object per line on stdout, ending with a `result` event
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown output format {s:?}, expected text or json"
+            )),
+        }
+    }
+}
+
+/// How much is shown besides errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Verbosity {
+    Quiet,
+    Normal,
+    /// debug logs, unless RUST_LOG says otherwise
+    Verbose,
+}
+
+struct Settings {
+    format: Format,
+    verbosity: Verbosity,
+    progress: bool,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Choose how output is shown. Progress bars are only drawn when both stdout and stderr
+/// are terminals, otherwise, like in CI jobs or editors, each step is logged on its own line.
+pub(crate) fn init(format: Format, verbosity: Verbosity, no_progress: bool) {
+    let progress = format == Format::Text
+        && verbosity != Verbosity::Quiet
+        && !no_progress
+        && std::io::stdout().is_terminal()
+        && std::io::stderr().is_terminal();
+    if no_color() {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+    let _ = SETTINGS.set(Settings {
+        format,
+        verbosity,
+        progress,
+    });
+}
+
+pub(crate) fn is_json() -> bool {
+    SETTINGS.get().is_some_and(|s| s.format == Format::Json)
+}
+
+pub(crate) fn verbosity() -> Verbosity {
+    SETTINGS.get().map_or(Verbosity::Normal, |s| s.verbosity)
+}
+
+pub(crate) fn is_quiet() -> bool {
+    verbosity() == Verbosity::Quiet
+}
+
+/// Whether steps are shown with progress bars
+pub(crate) fn shows_progress() -> bool {
+    SETTINGS.get().is_none_or(|s| s.progress)
+}
+
+/// Whether text output is logged line by line, instead of with progress bars
+pub(crate) fn is_plain() -> bool {
+    !is_json() && !is_quiet() && !shows_progress()
+}
+
+/// Print line telling what progress bar would show, in plain text output only
+pub(crate) fn plain_line(line: &str) {
+    if is_plain() {
+        println!("{line}");
+    }
+}
+
+/// Colors are disabled by NO_COLOR environment variable, see https://no-color.org
+fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// Whether logs written to stderr can be colored
+pub(crate) fn colored_logs() -> bool {
+    !no_color() && std::io::stderr().is_terminal()
+}
+
+/// Write event as a line of JSON to stdout, does nothing in text format
+pub(crate) fn event(name: &str, fields: Value) {
+    if !is_json() {
+        return;
+    }
+    let mut object = Map::new();
+    object.insert("event".to_string(), Value::from(name));
+    if let Value::Object(fields) = fields {
+        object.extend(fields);
+    }
+    println!("{}", Value::Object(object));
+}
+
+/// Exit codes, which stay the same between versions so that scripts can rely on them
+pub(crate) const EXIT_ERROR: i32 = 1;
+pub(crate) const EXIT_CONFLICT: i32 = 2;
+pub(crate) const EXIT_DIVERGED: i32 = 3;
+pub(crate) const EXIT_PUSH_REJECTED: i32 = 4;
+pub(crate) const EXIT_HOOK_FAILED: i32 = 5;
+pub(crate) const EXIT_LOCKED: i32 = 6;
+pub(crate) const EXIT_CORRUPTED_STATE: i32 = 7;
+pub(crate) const EXIT_CHECK_FAILED: i32 = 8;
+
+/// Category of failure and its exit code
+fn failure(e: &anyhow::Error) -> (&'static str, i32) {
+    for cause in e.chain() {
+        if cause.is::<crate::sup::ConflictError>() {
+            return ("conflict", EXIT_CONFLICT);
+        }
+        if cause.is::<crate::pull::DivergedError>() {
+            return ("diverged", EXIT_DIVERGED);
+        }
+        if cause.is::<crate::sup::PushRejectedError>() {
+            return ("push_rejected", EXIT_PUSH_REJECTED);
+        }
+        if cause.is::<crate::hooks::HookFailedError>() {
+            return ("hook_failed", EXIT_HOOK_FAILED);
+        }
+        if cause.is::<crate::lock::LockHeldError>() {
+            return ("locked", EXIT_LOCKED);
+        }
+        if cause.is::<crate::serde::CorruptedStateError>() {
+            return ("corrupted_state", EXIT_CORRUPTED_STATE);
+        }
+        if cause.is::<crate::check::CheckFailedError>() {
+            return ("check_failed", EXIT_CHECK_FAILED);
+        }
+    }
+    ("error", EXIT_ERROR)
+}
+
+/// Report outcome of the command and return exit code for it
+pub(crate) fn report(result: &anyhow::Result<()>) -> i32 {
+    match result {
+        Ok(()) => {
+            event("result", json!({ "status": "success", "exit_code": 0 }));
+            0
+        }
+        Err(e) => {
+            let (category, exit_code) = failure(e);
+            if is_json() {
+                let conflicted_files = e
+                    .chain()
+                    .find_map(|cause| cause.downcast_ref::<crate::sup::ConflictError>())
+                    .map(|conflict| conflict.files.clone())
+                    .unwrap_or_default();
+                event(
+                    "result",
+                    json!({
+                        "status": "failure",
+                        "category": category,
+                        "exit_code": exit_code,
+                        "error": e.to_string(),
+                        "conflicted_files": conflicted_files,
+                    }),
+                );
+            } else {
+                println!("Error: {e}");
+            }
+            exit_code
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! `sup --dry-run`: fetch upstream and show what sup would do, without touching
+//! HEAD, the index, the stash list or sup state.
+
+use crate::config::Config;
+use crate::pull::PullStrategy;
+use crate::sup::SupState;
+use anyhow::Result;
+use git2::{Commit, DiffOptions, MergeAnalysis, Oid, Repository, Tree};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeSet;
+
+/// How upstream changes would be integrated into local branch
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Integration {
+    UpToDate,
+    FastForward,
+    MergeCommit,
+    Rebase,
+    /// --ff-only with diverged branch
+    Refused,
+}
+
+/// What would happen to local changes when they are restored after pull
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LocalChanges {
+    None,
+    Clean,
+    Conflict,
+    /// pull itself would conflict, so there is nothing to restore changes onto
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+struct Preview {
+    upstream: String,
+    incoming: Vec<String>,
+    outgoing: Vec<String>,
+    changed_upstream: BTreeSet<String>,
+    changed_locally: BTreeSet<String>,
+    changed_on_both_sides: BTreeSet<String>,
+    integration: Integration,
+    /// whether local commits would conflict with incoming ones
+    pull_conflicts: bool,
+    local_changes: LocalChanges,
+    conflicting_files: BTreeSet<String>,
+    /// message of the commit which would be created and pushed
+    commit_message: Option<String>,
+}
+
+/// `command_line` has settings given as flags, see [`Config::load`]
+pub(crate) fn run_preview(command_line: &[(&str, String)], message: Option<&str>) -> Result<()> {
+    crate::sup::init_tracing();
+    let repo = crate::sup::open_repo()?;
+    let config = Config::load(&repo, command_line)?;
+    if SupState::load(&repo)? != SupState::Idle {
+        anyhow::bail!("Operation in progress, run `sup status` to see what to do next");
+    }
+    let preview = collect(&repo, &config, message)?;
+    if crate::output::is_json() {
+        crate::output::event("preview", json!(preview));
+    } else {
+        print_preview(&preview);
+    }
+    Ok(())
+}
+
+fn collect(repo: &Repository, config: &Config, message: Option<&str>) -> Result<Preview> {
+    let strategy = crate::sup::pull_strategy(repo, config)?;
+    let mut pulling = crate::pull::Pulling {
+        strategy,
+        remote: config.remote.clone(),
+        gpg_sign: config.gpg_sign,
+        no_verify: config.no_verify,
+    };
+    let (source, fetch_commit) = pulling.fetch_run(repo, &crate::pull::Args::default())?;
+    let upstream = repo.find_commit(fetch_commit.id())?;
+    let head = repo.head()?.peel_to_commit()?;
+
+    let (analysis, _) = repo.merge_analysis(&[&fetch_commit])?;
+    let integration = if analysis.contains(MergeAnalysis::ANALYSIS_UP_TO_DATE) {
+        Integration::UpToDate
+    } else if analysis.contains(MergeAnalysis::ANALYSIS_FASTFORWARD) {
+        Integration::FastForward
+    } else {
+        match pulling.strategy {
+            PullStrategy::Merge => Integration::MergeCommit,
+            PullStrategy::Rebase => Integration::Rebase,
+            PullStrategy::FastForwardOnly => Integration::Refused,
+        }
+    };
+
+    let base = repo
+        .merge_base(head.id(), upstream.id())
+        .ok()
+        .map(|oid| repo.find_commit(oid))
+        .transpose()?;
+    let base_tree = base.as_ref().map(Commit::tree).transpose()?;
+    let head_tree = head.tree()?;
+    let changed_upstream = changed_files(repo, base_tree.as_ref(), &upstream.tree()?)?;
+    let local_tree = local_changes_tree(repo, &head_tree)?;
+    let changed_locally = changed_files(repo, base_tree.as_ref(), &local_tree)?;
+    let changed_on_both_sides = changed_upstream
+        .intersection(&changed_locally)
+        .cloned()
+        .collect();
+
+    let pulled_tree = match integration {
+        Integration::UpToDate | Integration::Refused => Some(head.tree()?),
+        Integration::FastForward => Some(upstream.tree()?),
+        Integration::MergeCommit | Integration::Rebase => {
+            // rebase applies the same changes one by one, so its outcome is approximated by merge
+            let mut merged = repo.merge_commits(&head, &upstream, None)?;
+            if merged.has_conflicts() {
+                None
+            } else {
+                Some(repo.find_tree(merged.write_tree_to(repo)?)?)
+            }
+        }
+    };
+
+    let mut conflicting_files = BTreeSet::new();
+    let local_changes = if local_tree.id() == head_tree.id() {
+        LocalChanges::None
+    } else if let Some(ref pulled_tree) = pulled_tree {
+        let mut applied = repo.merge_trees(&head_tree, pulled_tree, &local_tree, None)?;
+        if applied.has_conflicts() {
+            for conflict in applied.conflicts()? {
+                let conflict = conflict?;
+                if let Some(entry) = conflict.our.or(conflict.their) {
+                    conflicting_files.insert(String::from_utf8_lossy(&entry.path).into_owned());
+                }
+            }
+            LocalChanges::Conflict
+        } else {
+            // make sure merged result is valid
+            applied.write_tree_to(repo)?;
+            LocalChanges::Clean
+        }
+    } else {
+        LocalChanges::Unknown
+    };
+
+    Ok(Preview {
+        upstream: format!("{}/{}", source.remote, source.branch()),
+        incoming: describe_commits(repo, upstream.id(), head.id())?,
+        outgoing: describe_commits(repo, head.id(), upstream.id())?,
+        changed_upstream,
+        changed_locally,
+        changed_on_both_sides,
+        pull_conflicts: pulled_tree.is_none(),
+        commit_message: message
+            .filter(|_| integration != Integration::Refused)
+            .map(str::to_string),
+        integration,
+        local_changes,
+        conflicting_files,
+    })
+}
+
+fn print_preview(preview: &Preview) {
+    let upstream = &preview.upstream;
+    println!(
+        "Incoming commits from {upstream}: {}",
+        preview.incoming.len()
+    );
+    print_lines(&preview.incoming);
+    println!("Local commits to push: {}", preview.outgoing.len());
+    print_lines(&preview.outgoing);
+    print_files("Files changed upstream", &preview.changed_upstream);
+    print_files("Files changed locally", &preview.changed_locally);
+    print_files(
+        "Files changed on both sides",
+        &preview.changed_on_both_sides,
+    );
+    match preview.integration {
+        Integration::UpToDate => println!("Pull: already up to date"),
+        Integration::FastForward => println!("Pull: fast-forward to {upstream}"),
+        Integration::MergeCommit => println!("Pull: merge commit would be created"),
+        Integration::Rebase => println!("Pull: local commits would be rebased onto {upstream}"),
+        Integration::Refused => {
+            println!("Pull: would be refused, branch has diverged from {upstream}")
+        }
+    }
+    if preview.pull_conflicts {
+        println!("  local commits would conflict with incoming ones");
+    }
+    match preview.local_changes {
+        LocalChanges::None => println!("Local changes: none to stash"),
+        LocalChanges::Clean => println!("Local changes: would be restored without conflicts"),
+        LocalChanges::Conflict => print_files(
+            "Local changes: would conflict when restored after pull",
+            &preview.conflicting_files,
+        ),
+        LocalChanges::Unknown => {
+            println!("Local changes: cannot predict, as pull itself would conflict")
+        }
+    }
+    if let Some(ref message) = preview.commit_message {
+        println!("Would commit local changes with message {message:?} and push");
+    }
+    println!("Dry run: nothing was changed except remote-tracking branch {upstream}");
+}
+
+/// Commits reachable from `from` but not from `hide`, newest first,
+/// as short id, subject and author
+fn describe_commits(repo: &Repository, from: Oid, hide: Oid) -> Result<Vec<String>> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(from)?;
+    revwalk.hide(hide)?;
+    revwalk
+        .map(|oid| {
+            let commit = repo.find_commit(oid?)?;
+            let id = commit.id().to_string();
+            Ok(format!(
+                "{} {} ({})",
+                &id[..7],
+                commit.summary().unwrap_or_default(),
+                commit.author().name().unwrap_or_default()
+            ))
+        })
+        .collect()
+}
+
+fn print_lines(lines: &[String]) {
+    for line in lines {
+        println!("  {line}");
+    }
+}
+
+fn print_files(title: &str, files: &BTreeSet<String>) {
+    if files.is_empty() {
+        return;
+    }
+    println!("{title}:");
+    for file in files {
+        println!("  {file}");
+    }
+}
+
+/// Tree which stash would record: HEAD with staged, unstaged and untracked changes,
+/// built in memory without touching the index
+fn local_changes_tree<'a>(repo: &'a Repository, head_tree: &Tree) -> Result<Tree<'a>> {
+    let mut options = DiffOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true)
+        .show_binary(true);
+    let diff = repo.diff_tree_to_workdir_with_index(Some(head_tree), Some(&mut options))?;
+    let mut index = repo.apply_to_tree(head_tree, &diff, None)?;
+    Ok(repo.find_tree(index.write_tree_to(repo)?)?)
+}
+
+fn changed_files(repo: &Repository, from: Option<&Tree>, to: &Tree) -> Result<BTreeSet<String>> {
+    let diff = repo.diff_tree_to_tree(from, Some(to), None)?;
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect())
+}
@@ -12,6 +12,7 @@
  * <http://creativecommons.org/publicdomain/zero/1.0/>.
  */
 
+use anyhow::Context;
 use console::Emoji;
 use git2::Repository;
 use indicatif::ProgressStyle;
@@ -19,20 +20,111 @@ use structopt::StructOpt;
 use tracing::{info_span, instrument, Span};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-#[derive(StructOpt)]
+#[derive(StructOpt, Default)]
 pub(crate) struct Args {
     pub(crate) arg_remote: Option<String>,
     pub(crate) arg_branch: Option<String>,
 }
 
-pub(crate) struct Pulling {}
+/// How fetched upstream changes are integrated into the local branch
+/// when history has diverged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PullStrategy {
+    /// Create a merge commit joining local and upstream history.
+    Merge,
+    /// Replay local commits on top of the upstream branch.
+    Rebase,
+    /// Only allow fast-forward, refusing to integrate diverged history.
+    FastForwardOnly,
+}
+
+impl std::str::FromStr for PullStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(PullStrategy::Merge),
+            "rebase" => Ok(PullStrategy::Rebase),
+            "ff-only" => Ok(PullStrategy::FastForwardOnly),
+            _ => Err(format!(
+                "unknown pull strategy {s:?}, expected merge, rebase or ff-only"
+            )),
+        }
+    }
+}
+
+impl PullStrategy {
+    /// Resolve strategy from git config the same way `git pull` does:
+    /// `branch.<name>.rebase` takes precedence over `pull.rebase`,
+    /// and `pull.ff=only` applies when history is not rebased.
+    pub(crate) fn from_config(
+        repo: &Repository,
+        branch: Option<&str>,
+    ) -> Result<Self, git2::Error> {
+        let config = repo.config()?;
+        let mut keys = Vec::new();
+        if let Some(branch) = branch {
+            keys.push(format!("branch.{branch}.rebase"));
+        }
+        keys.push("pull.rebase".to_string());
+        for key in keys {
+            if let Ok(value) = config.get_string(&key) {
+                tracing::debug!("Using {}={} to pick pull strategy", key, value);
+                match value.to_lowercase().as_str() {
+                    "false" | "no" | "off" | "0" | "" => break,
+                    // "merges" and "interactive" are not supported separately,
+                    // but both still mean that history should be rebased
+                    _ => return Ok(PullStrategy::Rebase),
+                }
+            }
+        }
+        if let Ok(value) = config.get_string("pull.ff") {
+            if value.eq_ignore_ascii_case("only") {
+                tracing::debug!("Using pull.ff=only to pick pull strategy");
+                return Ok(PullStrategy::FastForwardOnly);
+            }
+        }
+        Ok(PullStrategy::Merge)
+    }
+}
+
+/// Returned when fast-forward only pull finds that local branch
+/// has commits which upstream does not have.
+#[derive(Debug)]
+pub(crate) struct DivergedError {
+    pub(crate) remote_branch: String,
+}
+
+impl std::fmt::Display for DivergedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Not possible to fast-forward: local branch has diverged from upstream branch {}. \
+            Your local changes were restored. Integrate upstream changes with --rebase \
+            or a merge before trying again.",
+            self.remote_branch
+        )
+    }
+}
+
+impl std::error::Error for DivergedError {}
+
+pub(crate) struct Pulling {
+    pub(crate) strategy: PullStrategy,
+    /// remote to pull from instead of upstream of current branch
+    pub(crate) remote: Option<String>,
+    /// whether to sign merge commits, None to follow `commit.gpgSign` git config
+    pub(crate) gpg_sign: Option<bool>,
+    /// skip pre-merge-commit hook
+    pub(crate) no_verify: bool,
+}
 
 impl Pulling {
     fn do_fetch<'a>(
         &mut self,
         repo: &'a git2::Repository,
         refs: &[&str],
-        remote: &'a mut git2::Remote,
+        remote: &mut git2::Remote,
         remote_tracking_ref: &str,
     ) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
         let mut cb = git2::RemoteCallbacks::new();
@@ -53,8 +145,28 @@ impl Pulling {
 
         let mut deltas_span_entered = None;
         let mut objects_span = Some(objects_span.entered());
+        let mut reported_progress = None;
 
         cb.transfer_progress(move |stats| {
+            let percent = |done: usize, total: usize| (done * 100).checked_div(total).unwrap_or(0);
+            let progress = (
+                percent(stats.received_objects(), stats.total_objects()),
+                percent(stats.indexed_deltas(), stats.total_deltas()),
+            );
+            if reported_progress != Some(progress) {
+                reported_progress = Some(progress);
+                crate::output::event(
+                    "fetch_progress",
+                    serde_json::json!({
+                        "received_objects": stats.received_objects(),
+                        "total_objects": stats.total_objects(),
+                        "received_bytes": stats.received_bytes(),
+                        "indexed_deltas": stats.indexed_deltas(),
+                        "total_deltas": stats.total_deltas(),
+                    }),
+                );
+            }
+
             if let Some(processing_objects_span) = objects_span.as_mut() {
                 if !configured_objects_total {
                     if stats.total_objects() > 0 {
@@ -94,6 +206,11 @@ impl Pulling {
                     );
                     processing_objects_span.pb_tick();
                     processing_objects_span.pb_set_finish_message("");
+                    crate::output::plain_line(&format!(
+                        "Received {} objects ({})",
+                        stats.received_objects(),
+                        indicatif::HumanBytes(bytes)
+                    ));
                     tracing::debug!("Finished receiving objects");
                     if let Some(span) = objects_span.take() {
                         span.exit();
@@ -125,6 +242,10 @@ impl Pulling {
                     );
                     processing_deltas_span.pb_tick();
                     processing_deltas_span.pb_set_finish_message("");
+                    crate::output::plain_line(&format!(
+                        "Resolved {} deltas",
+                        stats.indexed_deltas()
+                    ));
                     tracing::debug!("Finished resolving deltas");
                     if let Some(span) = deltas_span_entered.take() {
                         span.exit();
@@ -174,17 +295,25 @@ impl Pulling {
     fn fast_forward(
         &mut self,
         repo: &Repository,
-        lb: &mut git2::Reference,
+        lb: Option<&mut git2::Reference>,
         rc: &git2::AnnotatedCommit,
     ) -> Result<(), git2::Error> {
-        let name = match lb.name() {
-            Some(s) => s.to_string(),
-            None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
-        };
-        let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-        tracing::debug!("{}", msg);
-        lb.set_target(rc.id(), &msg)?;
-        repo.set_head(&name)?;
+        match lb {
+            Some(lb) => {
+                let name = match lb.name() {
+                    Some(s) => s.to_string(),
+                    None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
+                };
+                let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
+                tracing::debug!("{}", msg);
+                lb.set_target(rc.id(), &msg)?;
+                repo.set_head(&name)?;
+            }
+            None => {
+                tracing::debug!("Fast-Forward: Setting detached HEAD to id: {}", rc.id());
+                repo.set_head_detached(rc.id())?;
+            }
+        }
         repo.checkout_head(Some(
             git2::build::CheckoutBuilder::default()
                 // For some reason the force is required to make the working directory actually get updated
@@ -200,7 +329,7 @@ impl Pulling {
         repo: &Repository,
         local: &git2::AnnotatedCommit,
         remote: &git2::AnnotatedCommit,
-    ) -> Result<(), git2::Error> {
+    ) -> anyhow::Result<()> {
         let local_tree = repo.find_commit(local.id())?.tree()?;
         let remote_tree = repo.find_commit(remote.id())?.tree()?;
         let ancestor = repo
@@ -233,7 +362,8 @@ impl Pulling {
                 .map_err(|e| git2::Error::from_str(&format!("Failed to create MERGE_MODE: {e}")))?;
             return Err(git2::Error::from_str(
                 "Merge conflicts detected, please resolve them manually.",
-            ));
+            )
+            .into());
         }
         let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
         // now create the merge commit
@@ -241,53 +371,149 @@ impl Pulling {
         let sig = repo.signature()?;
         let local_commit = repo.find_commit(local.id())?;
         let remote_commit = repo.find_commit(remote.id())?;
+        if !self.no_verify {
+            // nothing is changed yet, so failing hook leaves branch where it was
+            let author_env = crate::hooks::author_env(&sig);
+            crate::hooks::run_hook_with_env(repo, "pre-merge-commit", &[], &author_env)?;
+        }
         // Do our merge commit and set current branch head to that commit.
-        let _merge_commit = repo.commit(
-            Some("HEAD"),
+        crate::sign::commit(
+            repo,
             &sig,
             &sig,
             &msg,
             &result_tree,
             &[&local_commit, &remote_commit],
-        )?;
+            self.gpg_sign,
+        )
+        .context("Failed to create merge commit")?;
         // Set working tree to match head.
         repo.checkout_head(None)?;
         Ok(())
     }
 
+    fn rebase(
+        &mut self,
+        repo: &Repository,
+        upstream: &git2::AnnotatedCommit,
+    ) -> Result<(), git2::Error> {
+        let mut rebase = repo.rebase(None, Some(upstream), None, None)?;
+        self.replay(repo, &mut rebase)
+    }
+
+    /// Run post-checkout hook once rebase has moved HEAD, as `git rebase` does
+    fn run_post_checkout_hook(repo: &Repository, original_head: git2::Oid) {
+        if let Ok(head) = repo.head().and_then(|head| head.peel_to_commit()) {
+            let (old, new) = (original_head.to_string(), head.id().to_string());
+            crate::hooks::run_post_hook(repo, "post-checkout", &[&old, &new, "1"]);
+        }
+    }
+
+    /// Resume rebase that was paused on conflicts, assuming that user
+    /// has resolved them and staged the result.
+    #[instrument(skip_all)]
+    pub(crate) fn continue_rebase(&mut self, repo: &Repository) -> Result<(), git2::Error> {
+        crate::ui::step(
+            &Span::current(),
+            "continuing_rebase",
+            "Continuing rebase in progress",
+            &format!("{MERGE}Finished rebase"),
+        );
+        let mut rebase = repo.open_rebase(None)?;
+        if rebase.operation_current().is_some() {
+            self.commit_rebase_operation(repo, &mut rebase)?;
+        }
+        self.replay(repo, &mut rebase)
+    }
+
+    /// Abort rebase in progress, restoring the branch to where it was before.
+    pub(crate) fn abort_rebase(&mut self, repo: &Repository) -> Result<(), git2::Error> {
+        repo.open_rebase(None)?.abort()
+    }
+
+    fn replay(&mut self, repo: &Repository, rebase: &mut git2::Rebase) -> Result<(), git2::Error> {
+        while let Some(operation) = rebase.next() {
+            let operation = operation?;
+            tracing::debug!("Replaying commit {}", operation.id());
+            self.commit_rebase_operation(repo, rebase)?;
+        }
+        let sig = repo.signature()?;
+        rebase.finish(Some(&sig))?;
+        tracing::debug!("Rebase finished");
+        Ok(())
+    }
+
+    fn commit_rebase_operation(
+        &mut self,
+        repo: &Repository,
+        rebase: &mut git2::Rebase,
+    ) -> Result<(), git2::Error> {
+        if repo.index()?.has_conflicts() {
+            tracing::debug!("Rebase conflicts detected...");
+            return Err(git2::Error::from_str(
+                "Rebase conflicts detected, please resolve them manually.",
+            ));
+        }
+        let sig = repo.signature()?;
+        match rebase.commit(None, &sig, None) {
+            Ok(id) => {
+                tracing::debug!("Created rebased commit {}", id);
+                crate::sign::rebased_commit(repo, id, self.gpg_sign)
+                    .map_err(|e| git2::Error::from_str(&format!("{e:#}")))?;
+            }
+            Err(e) if e.code() == git2::ErrorCode::Applied => {
+                tracing::debug!("Changes are already present upstream, skipping commit")
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
     #[instrument(skip_all)]
     fn do_merge<'a>(
         &mut self,
         repo: &'a Repository,
+        local_ref: Option<&str>,
         remote_branch: &str,
         fetch_commit: git2::AnnotatedCommit<'a>,
-    ) -> Result<(), git2::Error> {
+    ) -> anyhow::Result<()> {
         configure_merge_progress(&Span::current(), remote_branch);
 
         // 1. do a merge analysis
         let analysis = repo.merge_analysis(&[&fetch_commit])?;
 
         tracing::debug!("Merge analysis: {:?}", analysis.0);
+        let merge_event = |result: &str| {
+            crate::output::event(
+                "merge",
+                serde_json::json!({ "result": result, "remote_branch": remote_branch }),
+            );
+        };
         // 2. Do the appopriate merge
         if analysis.0.is_fast_forward() {
             tracing::debug!("Doing a fast forward");
-            // do a fast forward
-            let refname = format!("refs/heads/{remote_branch}");
-            match repo.find_reference(&refname) {
+            merge_event("fast_forward");
+            // do a fast forward of the branch that HEAD points to
+            let Some(refname) = local_ref else {
+                self.fast_forward(repo, None, &fetch_commit)?;
+                crate::hooks::run_post_hook(repo, "post-merge", &["0"]);
+                return Ok(());
+            };
+            match repo.find_reference(refname) {
                 Ok(mut r) => {
-                    self.fast_forward(repo, &mut r, &fetch_commit)?;
+                    self.fast_forward(repo, Some(&mut r), &fetch_commit)?;
                 }
                 Err(_) => {
                     // The branch doesn't exist so just set the reference to the
                     // commit directly. Usually this is because you are pulling
                     // into an empty repository.
                     repo.reference(
-                        &refname,
+                        refname,
                         fetch_commit.id(),
                         true,
-                        &format!("Setting {} to {}", remote_branch, fetch_commit.id()),
+                        &format!("Setting {} to {}", refname, fetch_commit.id()),
                     )?;
-                    repo.set_head(&refname)?;
+                    repo.set_head(refname)?;
                     repo.checkout_head(Some(
                         git2::build::CheckoutBuilder::default()
                             .allow_conflicts(true)
@@ -296,36 +522,82 @@ impl Pulling {
                     ))?;
                 }
             };
+            // argument tells whether it was a squash merge
+            crate::hooks::run_post_hook(repo, "post-merge", &["0"]);
         } else if analysis.0.is_normal() {
-            tracing::debug!("Doing a normal merge");
-            // do a normal merge
-            let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-            self.normal_merge(repo, &head_commit, &fetch_commit)?;
+            match self.strategy {
+                PullStrategy::FastForwardOnly => {
+                    tracing::debug!("Refusing to merge diverged history");
+                    merge_event("diverged");
+                    return Err(DivergedError {
+                        remote_branch: remote_branch.to_string(),
+                    }
+                    .into());
+                }
+                PullStrategy::Merge => {
+                    tracing::debug!("Doing a normal merge");
+                    merge_event("merge_commit");
+                    // do a normal merge
+                    let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
+                    self.normal_merge(repo, &head_commit, &fetch_commit)?;
+                    crate::hooks::run_post_hook(repo, "post-merge", &["0"]);
+                }
+                PullStrategy::Rebase => {
+                    tracing::debug!("Doing a rebase");
+                    merge_event("rebase");
+                    crate::ui::set_finish_message(
+                        &Span::current(),
+                        &format!("{MERGE}Rebased onto branch {remote_branch}"),
+                    );
+                    let original_head = repo.head()?.peel_to_commit()?.id();
+                    self.rebase(repo, &fetch_commit)?;
+                    Self::run_post_checkout_hook(repo, original_head);
+                }
+            }
         } else {
             tracing::debug!("Nothing to merge, continue");
+            merge_event("up_to_date");
         }
         Ok(())
     }
 
-    pub(crate) fn pull_run(&mut self, args: &Args) -> Result<(), git2::Error> {
-        let remote_name = args.arg_remote.as_ref().map(|s| &s[..]).unwrap_or("origin");
-        let remote_branch = args.arg_branch.as_ref().map(|s| &s[..]).unwrap_or("master");
-        tracing::debug!("Pulling from remote: {}/{}", remote_name, remote_branch);
-        let repo = Repository::open(".")?;
-        let mut remote = repo.find_remote(remote_name)?;
+    /// Fetch upstream branch into its remote-tracking reference, without merging it
+    pub(crate) fn fetch_run<'a>(
+        &mut self,
+        repo: &'a Repository,
+        args: &Args,
+    ) -> anyhow::Result<(crate::upstream::PullSource, git2::AnnotatedCommit<'a>)> {
+        let source = crate::upstream::pull_source(
+            repo,
+            args.arg_remote.as_deref().or(self.remote.as_deref()),
+            args.arg_branch.as_deref(),
+        )?;
+        tracing::debug!("Fetching from remote: {}/{}", source.remote, source.branch());
+        let mut remote = repo.find_remote(&source.remote)?;
 
         // Build refspec: refs/heads/main:refs/remotes/origin/main
-        let refspec =
-            format!("refs/heads/{remote_branch}:refs/remotes/{remote_name}/{remote_branch}",);
-        let remote_refname = format!("refs/remotes/{remote_name}/{remote_branch}");
-        let fetch_commit = self.do_fetch(&repo, &[&refspec], &mut remote, &remote_refname)?;
-        self.do_merge(&repo, remote_branch, fetch_commit)
+        let remote_refname = source.tracking_ref();
+        let refspec = format!("{}:{}", source.merge, remote_refname);
+        let fetch_commit = self.do_fetch(repo, &[&refspec], &mut remote, &remote_refname)?;
+        Ok((source, fetch_commit))
+    }
+
+    pub(crate) fn pull_run(&mut self, repo: &Repository, args: &Args) -> anyhow::Result<()> {
+        let (source, fetch_commit) = self.fetch_run(repo, args)?;
+        // local branch that HEAD points to, if not detached
+        let head = repo.find_reference("HEAD")?;
+        let local_ref = head.symbolic_target().map(str::to_string);
+        self.do_merge(repo, local_ref.as_deref(), source.branch(), fetch_commit)
     }
 }
 
 static MERGE: Emoji<'_, '_> = Emoji("🔀  ", "");
 
 fn configure_merge_progress(span: &Span, remote_branch: &str) {
-    span.pb_set_message("Merging changes");
-    span.pb_set_finish_message(&format!("{MERGE}Merged branch {remote_branch}"));
+    crate::ui::step(
+        span,
+        "merging",
+        "Merging changes",
+        &format!("{MERGE}Merged branch {remote_branch}"),
+    );
 }
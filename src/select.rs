@@ -0,0 +1,129 @@
+//! Selection of changes to commit with `--message`, by pathspecs given after `--`
+//! or interactively. Changes which are not selected are left in working tree.
+
+use anyhow::{Context, Result};
+use git2::{Index, Oid, Pathspec, PathspecFlags, Repository, Status, StatusOptions, Tree};
+use std::io::IsTerminal;
+use std::path::{Component, Path, PathBuf};
+
+/// Turn pathspecs relative to current directory into ones relative to
+/// the root of working tree, as git does when run from a subdirectory
+pub(crate) fn repo_relative(repo: &Repository, paths: &[String]) -> Result<Vec<String>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let workdir = repo
+        .workdir()
+        .context("sup needs a working tree")?
+        .canonicalize()?;
+    let cwd = std::env::current_dir()?.canonicalize()?;
+    paths
+        .iter()
+        .map(|path| {
+            let absolute = normalize(&cwd.join(path));
+            let relative = absolute.strip_prefix(&workdir).with_context(|| {
+                format!("{path} is outside of repository {}", workdir.display())
+            })?;
+            let relative = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            Ok(if relative.is_empty() {
+                "*".to_string()
+            } else {
+                relative
+            })
+        })
+        .collect()
+}
+
+/// Resolve `.` and `..` without touching filesystem, as selected paths could be deleted files
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Files with changes that could be committed, only staged ones if `staged` is set,
+/// limited to `paths` unless they are empty
+pub(crate) fn changed_files(
+    repo: &Repository,
+    staged: bool,
+    paths: &[String],
+) -> Result<Vec<String>> {
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(!staged)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false);
+    for path in paths {
+        options.pathspec(path);
+    }
+    let index_changes = Status::INDEX_NEW
+        | Status::INDEX_MODIFIED
+        | Status::INDEX_DELETED
+        | Status::INDEX_RENAMED
+        | Status::INDEX_TYPECHANGE;
+    let files = repo
+        .statuses(Some(&mut options))?
+        .iter()
+        .filter(|entry| !staged || entry.status().intersects(index_changes))
+        .filter(|entry| !entry.status().is_ignored() && entry.status() != Status::CURRENT)
+        .filter_map(|entry| entry.path().map(str::to_string))
+        .collect();
+    Ok(files)
+}
+
+/// Let user pick which of the changed files to commit
+pub(crate) fn choose_interactively(files: &[String]) -> Result<Vec<String>> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "--interactive needs a terminal to choose files, pass paths after `--` instead"
+        );
+    }
+    let chosen = dialoguer::MultiSelect::new()
+        .with_prompt("Choose files to commit (space to select, enter to confirm)")
+        .items(files)
+        .interact()?;
+    if chosen.is_empty() {
+        anyhow::bail!("No files were chosen to commit");
+    }
+    Ok(chosen.into_iter().map(|i| files[i].clone()).collect())
+}
+
+/// Tree to commit: `parent` with entries matching `paths` taken from `index`
+pub(crate) fn tree_with_paths(
+    repo: &Repository,
+    index: &Index,
+    parent: &Tree,
+    paths: &[String],
+) -> Result<Oid> {
+    let pathspec = Pathspec::new(paths)?;
+    let matches = |path: &[u8]| {
+        let path = String::from_utf8_lossy(path);
+        pathspec.matches_path(Path::new(path.as_ref()), PathspecFlags::DEFAULT)
+    };
+    let mut selected = Index::new()?;
+    selected.read_tree(parent)?;
+    let removed: Vec<Vec<u8>> = selected
+        .iter()
+        .filter(|entry| matches(&entry.path))
+        .map(|entry| entry.path)
+        .collect();
+    for path in removed {
+        selected.remove(Path::new(String::from_utf8_lossy(&path).as_ref()), 0)?;
+    }
+    for entry in index.iter().filter(|entry| matches(&entry.path)) {
+        selected.add(&entry)?;
+    }
+    Ok(selected.write_tree_to(repo)?)
+}
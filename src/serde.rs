@@ -1,51 +1,138 @@
+//! Format of the state file. Current format is versioned and uses named fields,
+//! older formats are migrated when read.
+
 use crate::sup::SupState;
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Version of the state file format written by this version of sup, which is bumped whenever
+/// fields are added, so that older sup refuses state it would not fully understand:
+/// - 2: named fields
+/// - 3: `staged`, `paths`, `check` and `committed`, which default to empty when missing
+pub(crate) const STATE_VERSION: u64 = 3;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub(crate) enum SupStateSerde {
-    Idle,
-    InProgress(bool, String, Option<String>),
-    Interrupted(bool, String, Option<String>, bool),
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    version: u64,
+    state: SupState,
 }
 
-impl From<SupState> for SupStateSerde {
-    fn from(state: SupState) -> Self {
-        match state {
-            SupState::Idle => SupStateSerde::Idle,
-            SupState::InProgress {
-                stash_created,
-                original_head,
-                message,
-            } => SupStateSerde::InProgress(stash_created, original_head, message),
-            SupState::Interrupted {
-                stash_created,
-                original_head,
-                message,
-                stash_applied,
-            } => SupStateSerde::Interrupted(stash_created, original_head, message, stash_applied),
-        }
+/// Returned when state file cannot be understood
+#[derive(Debug)]
+pub(crate) struct CorruptedStateError {
+    pub(crate) path: PathBuf,
+    pub(crate) reason: String,
+}
+
+impl std::fmt::Display for CorruptedStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sup state file {} is corrupted: {}. Check `git stash list` for changes stashed by sup, \
+            then remove the file to start over",
+            self.path.display(),
+            self.reason
+        )
     }
 }
 
-impl From<SupStateSerde> for SupState {
-    fn from(state: SupStateSerde) -> Self {
-        match state {
-            SupStateSerde::Idle => SupState::Idle,
-            SupStateSerde::InProgress(stash_created, original_head, message) => {
-                SupState::InProgress {
-                    stash_created,
-                    original_head,
-                    message,
-                }
+impl std::error::Error for CorruptedStateError {}
+
+pub(crate) fn to_string(state: &SupState) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&StateFile {
+        version: STATE_VERSION,
+        state: state.clone(),
+    })?)
+}
+
+pub(crate) fn from_str(path: &Path, content: &str) -> Result<SupState> {
+    let corrupted = |reason: &dyn std::fmt::Display| -> anyhow::Error {
+        CorruptedStateError {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+        .into()
+    };
+    let value: Value = serde_json::from_str(content).map_err(|e| corrupted(&e))?;
+    match value.get("version") {
+        Some(version) => {
+            let version = version
+                .as_u64()
+                .ok_or_else(|| corrupted(&format!("invalid version {version}")))?;
+            if version > STATE_VERSION {
+                anyhow::bail!(
+                    "sup state file was written by newer version of sup (format version {version}), \
+                    upgrade sup to continue"
+                );
             }
-            SupStateSerde::Interrupted(stash_created, original_head, message, stash_applied) => {
-                SupState::Interrupted {
-                    stash_created,
-                    original_head,
-                    message,
-                    stash_applied,
-                }
+            if version < STATE_VERSION {
+                tracing::debug!("Migrating sup state from format version {}", version);
             }
+            // fields added since are filled in with their serde defaults
+            let file: StateFile = serde_json::from_value(value).map_err(|e| corrupted(&e))?;
+            Ok(file.state)
+        }
+        None => from_legacy(&value).map_err(|reason| corrupted(&reason)),
+    }
+}
+
+/// Migrate state written by sup before the format was versioned, which stored
+/// variants with positional fields, like `{"Interrupted":[false,"<oid>",null,false]}`.
+/// Fields were only ever appended, so missing trailing ones get defaults.
+fn from_legacy(value: &Value) -> Result<SupState, String> {
+    let (variant, fields) = match value {
+        Value::String(variant) => (variant.as_str(), &[][..]),
+        Value::Object(map) if map.len() == 1 => {
+            let (variant, fields) = map.iter().next().expect("map has one entry");
+            let fields = fields
+                .as_array()
+                .ok_or_else(|| format!("fields of {variant} are not a list"))?;
+            (variant.as_str(), fields.as_slice())
+        }
+        _ => return Err(format!("unknown state {value}")),
+    };
+    let bool_at = |i: usize| -> Result<bool, String> {
+        match fields.get(i) {
+            None | Some(Value::Null) => Ok(false),
+            Some(Value::Bool(b)) => Ok(*b),
+            Some(other) => Err(format!("expected true or false, got {other}")),
+        }
+    };
+    let string_at = |i: usize| -> Result<Option<String>, String> {
+        match fields.get(i) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(other) => Err(format!("expected text, got {other}")),
         }
+    };
+    let original_head = || -> Result<String, String> {
+        string_at(1)?.ok_or_else(|| format!("{variant} has no original head"))
+    };
+    tracing::debug!("Migrating sup state from unversioned format: {}", value);
+    match variant {
+        "Idle" => Ok(SupState::Idle),
+        "InProgress" => Ok(SupState::InProgress {
+            stash_created: bool_at(0)?,
+            original_head: original_head()?,
+            message: string_at(2)?,
+            stash_oid: string_at(3)?,
+            paths: Vec::new(),
+            staged: false,
+        }),
+        "Interrupted" => Ok(SupState::Interrupted {
+            stash_created: bool_at(0)?,
+            original_head: original_head()?,
+            message: string_at(2)?,
+            stash_applied: bool_at(3)?,
+            rebasing: bool_at(4)?,
+            stash_oid: string_at(5)?,
+            paths: Vec::new(),
+            staged: false,
+            check: None,
+            committed: false,
+        }),
+        _ => Err(format!("unknown state {variant}")),
     }
 }
@@ -0,0 +1,187 @@
+//! Signing of commits created by sup, configured the same way as for `git commit`:
+//! `commit.gpgSign`, `gpg.format` (openpgp, x509 or ssh), `user.signingKey`,
+//! `gpg.program` and `gpg.<format>.program`.
+
+use anyhow::{Context, Result};
+use git2::{Commit, Oid, Repository, Signature, Tree};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Create commit and move HEAD to it, like `repo.commit(Some("HEAD"), ...)` does,
+/// signing it when `gpg_sign` or, when it is not given, `commit.gpgSign` git config says so
+pub(crate) fn commit(
+    repo: &Repository,
+    author: &Signature,
+    committer: &Signature,
+    message: &str,
+    tree: &Tree,
+    parents: &[&Commit],
+    gpg_sign: Option<bool>,
+) -> Result<Oid> {
+    if !should_sign(repo, gpg_sign)? {
+        return Ok(repo.commit(Some("HEAD"), author, committer, message, tree, parents)?);
+    }
+    let oid = write_signed(repo, author, committer, message, tree, parents)?;
+
+    // commit_signed only writes the commit, so move branch HEAD points to, as `git commit` does
+    let summary = message.lines().next().unwrap_or_default();
+    let kind = if parents.len() > 1 {
+        "commit (merge)"
+    } else {
+        "commit"
+    };
+    repo.find_reference("HEAD")?
+        .resolve()?
+        .set_target(oid, &format!("{kind}: {summary}"))?;
+    tracing::debug!("Created signed commit {}", oid);
+    Ok(oid)
+}
+
+/// Replace commit just made by rebase with its signed copy, moving detached HEAD to it,
+/// so that the next commit replayed by rebase is made on top of the signed one
+pub(crate) fn rebased_commit(repo: &Repository, id: Oid, gpg_sign: Option<bool>) -> Result<Oid> {
+    if !should_sign(repo, gpg_sign)? {
+        return Ok(id);
+    }
+    let commit = repo.find_commit(id)?;
+    let parents: Vec<Commit> = commit.parents().collect();
+    let parents: Vec<&Commit> = parents.iter().collect();
+    let message = commit
+        .message()
+        .context("commit message is not valid UTF-8")?;
+    let oid = write_signed(
+        repo,
+        &commit.author(),
+        &commit.committer(),
+        message,
+        &commit.tree()?,
+        &parents,
+    )?;
+    let summary = commit.summary().unwrap_or_default();
+    repo.find_reference("HEAD")?
+        .set_target(oid, &format!("rebase (pick): {summary}"))?;
+    tracing::debug!("Signed rebased commit {} as {}", id, oid);
+    Ok(oid)
+}
+
+/// Whether `gpg_sign` or, when it is not given, `commit.gpgSign` git config says to sign
+fn should_sign(repo: &Repository, gpg_sign: Option<bool>) -> Result<bool> {
+    let config = repo.config()?;
+    Ok(gpg_sign.unwrap_or_else(|| config.get_bool("commit.gpgSign").unwrap_or(false)))
+}
+
+/// Write signed commit without moving any reference
+fn write_signed(
+    repo: &Repository,
+    author: &Signature,
+    committer: &Signature,
+    message: &str,
+    tree: &Tree,
+    parents: &[&Commit],
+) -> Result<Oid> {
+    let buffer = repo.commit_create_buffer(author, committer, message, tree, parents)?;
+    let buffer = buffer.as_str().context("commit is not valid UTF-8")?;
+    let signature = sign_buffer(repo, buffer, committer)?;
+    Ok(repo.commit_signed(buffer, &signature, None)?)
+}
+
+fn sign_buffer(repo: &Repository, buffer: &str, committer: &Signature) -> Result<String> {
+    let config = repo.config()?;
+    let get = |key: &str| config.get_string(key).ok();
+    let format = get("gpg.format").unwrap_or_else(|| "openpgp".to_string());
+    let key = get("user.signingKey");
+    match format.as_str() {
+        "openpgp" | "x509" => {
+            let program = get(&format!("gpg.{format}.program"))
+                .or_else(|| {
+                    if format == "openpgp" {
+                        get("gpg.program")
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or_else(|| if format == "openpgp" { "gpg" } else { "gpgsm" }.to_string());
+            // without signing key, gpg picks key by committer identity
+            let key = key.unwrap_or_else(|| {
+                format!(
+                    "{} <{}>",
+                    committer.name().unwrap_or_default(),
+                    committer.email().unwrap_or_default()
+                )
+            });
+            let mut command = Command::new(&program);
+            command.args(["--status-fd=2", "-bsau", &key]);
+            let output = run_with_input(&mut command, buffer.as_bytes())
+                .with_context(|| format!("failed to run {program} to sign commit"))?;
+            let status = String::from_utf8_lossy(&output.stderr);
+            if !output.status.success() || !status.contains("[GNUPG:] SIG_CREATED ") {
+                anyhow::bail!("{program} failed to sign commit: {}", status.trim());
+            }
+            Ok(String::from_utf8(output.stdout).context("signature is not valid UTF-8")?)
+        }
+        "ssh" => {
+            let program = get("gpg.ssh.program").unwrap_or_else(|| "ssh-keygen".to_string());
+            let key =
+                key.context("user.signingKey has to be set to sign commits with gpg.format=ssh")?;
+            // literal public key means private key is in ssh-agent
+            let literal = key
+                .strip_prefix("key::")
+                .or_else(|| key.starts_with("ssh-").then_some(key.as_str()));
+            let mut key_file = None;
+            let mut command = Command::new(&program);
+            command.args(["-Y", "sign", "-n", "git", "-f"]);
+            match literal {
+                Some(literal) => {
+                    let mut file = tempfile::NamedTempFile::new()?;
+                    file.write_all(literal.as_bytes())?;
+                    command.arg(file.path()).arg("-U");
+                    key_file = Some(file);
+                }
+                None => {
+                    command.arg(expand_home(&key));
+                }
+            }
+            let mut buffer_file = tempfile::NamedTempFile::new()?;
+            buffer_file.write_all(buffer.as_bytes())?;
+            command.arg(buffer_file.path());
+            let output = command
+                .stdin(Stdio::null())
+                .output()
+                .with_context(|| format!("failed to run {program} to sign commit"))?;
+            drop(key_file);
+            let mut signature_path = buffer_file.path().as_os_str().to_owned();
+            signature_path.push(".sig");
+            let signature = std::fs::read_to_string(&signature_path);
+            let _ = std::fs::remove_file(&signature_path);
+            if !output.status.success() {
+                anyhow::bail!(
+                    "{program} failed to sign commit: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(signature.context("failed to read ssh signature")?)
+        }
+        other => anyhow::bail!("Unsupported gpg.format {other:?}, expected openpgp, x509 or ssh"),
+    }
+}
+
+fn run_with_input(command: &mut Command, input: &[u8]) -> std::io::Result<std::process::Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+    child.wait_with_output()
+}
+
+/// Signing key path can start with `~/`, as git allows
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
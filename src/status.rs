@@ -0,0 +1,225 @@
+//! `sup status`: explain what state sup left the repository in and what to do next.
+
+use crate::sup::SupState;
+use anyhow::Result;
+use git2::{Repository, RepositoryState};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct Status {
+    /// idle, in_progress or interrupted
+    state: &'static str,
+    /// whether another sup process is holding the lock right now
+    running: bool,
+    original_head: Option<String>,
+    message: Option<String>,
+    /// paths chosen to commit, empty when all changes are committed
+    paths: Vec<String>,
+    /// whether only staged changes are committed
+    staged: bool,
+    stash_created: bool,
+    stash_applied: bool,
+    stash_oid: Option<String>,
+    rebasing: bool,
+    /// command which has to succeed before push
+    check: Option<String>,
+    /// whether changes were committed and only check and push are left,
+    /// after check failed or upstream changes could not be integrated for push retry
+    committed: bool,
+    /// state of git operation in progress, like merge or rebase
+    repository_state: &'static str,
+    conflicted_files: Vec<String>,
+    /// commands which would move sup forward from here
+    next_commands: Vec<&'static str>,
+    hint: String,
+}
+
+pub fn run_status(json: bool) -> Result<()> {
+    let repo = crate::sup::open_repo()?;
+    let status = collect(&repo)?;
+    if crate::output::is_json() {
+        // single line, so that output stays JSON Lines
+        println!("{}", serde_json::to_string(&status)?);
+    } else if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print_status(&status);
+    }
+    Ok(())
+}
+
+fn collect(repo: &Repository) -> Result<Status> {
+    let running = crate::lock::holder(repo)?.is_some_and(|holder| holder.is_alive());
+    let mut status = Status {
+        state: "idle",
+        running,
+        original_head: None,
+        message: None,
+        paths: Vec::new(),
+        staged: false,
+        stash_created: false,
+        stash_applied: false,
+        stash_oid: None,
+        rebasing: false,
+        check: None,
+        committed: false,
+        repository_state: repository_state(repo.state()),
+        conflicted_files: conflicted_files(repo)?,
+        next_commands: Vec::new(),
+        hint: String::new(),
+    };
+    match SupState::load(repo)? {
+        SupState::Idle => {
+            status.hint = if running {
+                "sup is running".to_string()
+            } else {
+                "No sup operation in progress".to_string()
+            };
+        }
+        SupState::InProgress {
+            stash_created,
+            original_head,
+            message,
+            stash_oid,
+            paths,
+            staged,
+        } => {
+            status.state = "in_progress";
+            status.original_head = Some(original_head);
+            status.message = message;
+            status.paths = paths;
+            status.staged = staged;
+            status.stash_created = stash_created;
+            status.stash_oid = stash_oid;
+            status.hint = if running {
+                "sup is running".to_string()
+            } else {
+                stopped_hint(repo)
+            };
+        }
+        SupState::Interrupted {
+            stash_created,
+            stash_applied,
+            original_head,
+            message,
+            rebasing,
+            stash_oid,
+            paths,
+            staged,
+            check,
+            committed,
+        } => {
+            status.state = "interrupted";
+            status.original_head = Some(original_head);
+            status.message = message;
+            status.paths = paths;
+            status.staged = staged;
+            status.stash_created = stash_created;
+            status.stash_applied = stash_applied;
+            status.stash_oid = stash_oid;
+            status.rebasing = rebasing;
+            status.check = check;
+            status.committed = committed;
+            status.next_commands = vec!["sup continue", "sup abort"];
+            status.hint = if committed && !status.conflicted_files.is_empty() {
+                "Upstream changes pulled before push retry conflict with the commit. \
+                Resolve conflicts, stage them with `git add` and run `sup continue` to push, \
+                or run `sup abort` to roll back"
+            } else if committed {
+                "Commit was not pushed. Fix the problem, amend the commit \
+                or add another one, then run `sup continue` to check again and push, \
+                or `sup abort` to roll back"
+            } else if status.conflicted_files.is_empty() {
+                "Run `sup continue` to finish the operation, or `sup abort` to roll back"
+            } else {
+                "Resolve conflicts, stage them with `git add` and run `sup continue`, \
+                or run `sup abort` to roll back"
+            }
+            .to_string();
+        }
+    }
+    Ok(status)
+}
+
+fn print_status(status: &Status) {
+    println!("sup state: {}", status.state.replace('_', " "));
+    if let Some(ref original_head) = status.original_head {
+        println!("  original head: {original_head}");
+    }
+    if let Some(ref message) = status.message {
+        println!("  commit message: {message:?}");
+    }
+    if !status.paths.is_empty() {
+        println!("  paths to commit: {}", status.paths.join(" "));
+    }
+    if status.staged {
+        println!("  committing only staged changes");
+    }
+    if status.state != "idle" {
+        let stash = match (status.stash_created, status.stash_applied) {
+            (false, _) => "not created".to_string(),
+            (true, false) => "created, not applied yet".to_string(),
+            (true, true) => "created and applied".to_string(),
+        };
+        match status.stash_oid {
+            Some(ref stash_oid) => println!("  stash: {stash} ({stash_oid})"),
+            None => println!("  stash: {stash}"),
+        }
+    }
+    if status.rebasing {
+        println!("  rebasing local commits onto upstream");
+    }
+    if let Some(ref check) = status.check {
+        println!("  check before push: {check}");
+    }
+    if status.committed {
+        println!("  changes are committed, not pushed yet");
+    }
+    println!(
+        "  repository state: {}",
+        status.repository_state.replace('_', " ")
+    );
+    if !status.conflicted_files.is_empty() {
+        println!("  conflicted files:");
+        for file in &status.conflicted_files {
+            println!("    {file}");
+        }
+    }
+    println!("{}", status.hint);
+}
+
+/// How to recover when sup was stopped while its operation was in progress
+pub(crate) fn stopped_hint(repo: &Repository) -> String {
+    format!(
+        "sup was stopped before it could record how to recover. \
+        Check `git stash list` for the sup stash, apply it if needed, \
+        and remove {}",
+        SupState::path(repo).display()
+    )
+}
+
+pub(crate) fn conflicted_files(repo: &Repository) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in repo.statuses(None)?.iter() {
+        if entry.status().is_conflicted() {
+            if let Some(path) = entry.path() {
+                files.push(path.to_string());
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn repository_state(state: RepositoryState) -> &'static str {
+    match state {
+        RepositoryState::Clean => "clean",
+        RepositoryState::Merge => "merge",
+        RepositoryState::Revert | RepositoryState::RevertSequence => "revert",
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => "cherry_pick",
+        RepositoryState::Bisect => "bisect",
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge => "rebase",
+        RepositoryState::ApplyMailbox | RepositoryState::ApplyMailboxOrRebase => "apply_mailbox",
+    }
+}
@@ -0,0 +1,145 @@
+//! Summary of changes brought by pull, with warnings about changes which usually
+//! need some action from developer, like reinstalling dependencies or running migrations.
+
+use anyhow::Result;
+use git2::{DiffStatsFormat, Oid, Pathspec, PathspecFlags, Repository};
+use serde::Serialize;
+use std::path::Path;
+
+/// Lockfiles of package managers, which mean dependencies need to be reinstalled
+const LOCKFILES: &[&str] = &[
+    "Cargo.lock",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "bun.lockb",
+    "Gemfile.lock",
+    "poetry.lock",
+    "Pipfile.lock",
+    "uv.lock",
+    "composer.lock",
+    "go.sum",
+    "mix.lock",
+    "pubspec.lock",
+];
+
+/// Directories with database migrations
+const MIGRATION_DIRS: &[&str] = &["migrations", "migrate", "migration"];
+
+/// How many pulled commits are listed, the rest are only counted
+const MAX_LISTED_COMMITS: usize = 10;
+
+#[derive(Serialize)]
+pub(crate) struct PullSummary {
+    pub(crate) commits: usize,
+    pub(crate) authors: Vec<String>,
+    /// one line per commit: short id, subject and author, newest first
+    pub(crate) log: Vec<String>,
+    pub(crate) diffstat: String,
+    pub(crate) lockfiles: Vec<String>,
+    pub(crate) migrations: Vec<String>,
+    /// changed paths matching `sup.watch` setting
+    pub(crate) watched: Vec<String>,
+}
+
+/// Summarize what was pulled from `upstream` commit which was not in `original_head` yet,
+/// pointing out changes in `watch` pathspecs, None when nothing was pulled.
+/// Local commits, even when rebased onto upstream, are not part of it.
+pub(crate) fn pull_summary(
+    repo: &Repository,
+    original_head: &str,
+    upstream: Oid,
+    watch: &[String],
+) -> Result<Option<PullSummary>> {
+    let original = repo.find_commit(Oid::from_str(original_head)?)?;
+    let upstream = repo.find_commit(upstream)?;
+    let base = repo.merge_base(original.id(), upstream.id())?;
+    if base == upstream.id() {
+        return Ok(None);
+    }
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(upstream.id())?;
+    revwalk.hide(original.id())?;
+    revwalk.hide(base)?;
+    let mut commits = 0;
+    let mut authors: Vec<String> = Vec::new();
+    let mut log = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        // merges are not counted, as with `git log --no-merges`
+        if commit.parent_count() > 1 {
+            continue;
+        }
+        commits += 1;
+        let author = commit.author().name().unwrap_or_default().to_string();
+        if log.len() < MAX_LISTED_COMMITS {
+            let id = commit.id().to_string();
+            log.push(format!(
+                "{} {} ({author})",
+                &id[..7],
+                commit.summary().unwrap_or_default()
+            ));
+        }
+        if !authors.contains(&author) {
+            authors.push(author);
+        }
+    }
+
+    let base = repo.find_commit(base)?;
+    let diff = repo.diff_tree_to_tree(Some(&base.tree()?), Some(&upstream.tree()?), None)?;
+    let stats = diff.stats()?;
+    let format = if stats.files_changed() > 20 {
+        DiffStatsFormat::SHORT
+    } else {
+        DiffStatsFormat::FULL
+    };
+    let diffstat = stats
+        .to_buf(format, 80)?
+        .as_str()
+        .unwrap_or_default()
+        .trim_end()
+        .to_string();
+
+    let changed: Vec<String> = diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .collect();
+    let lockfiles = changed
+        .iter()
+        .filter(|path| {
+            let name = Path::new(path).file_name().unwrap_or_default();
+            LOCKFILES.iter().any(|lockfile| name == *lockfile)
+        })
+        .cloned()
+        .collect();
+    let migrations = changed
+        .iter()
+        .filter(|path| {
+            let mut dirs = path.split('/').rev().skip(1);
+            dirs.any(|dir| MIGRATION_DIRS.contains(&dir))
+        })
+        .cloned()
+        .collect();
+    let watched = if watch.is_empty() {
+        Vec::new()
+    } else {
+        let pathspec = Pathspec::new(watch.iter())?;
+        changed
+            .iter()
+            .filter(|path| pathspec.matches_path(Path::new(path), PathspecFlags::DEFAULT))
+            .cloned()
+            .collect()
+    };
+
+    Ok(Some(PullSummary {
+        commits,
+        authors,
+        log,
+        diffstat,
+        lockfiles,
+        migrations,
+        watched,
+    }))
+}
@@ -1,266 +1,566 @@
+use crate::config::Config;
 use crate::hooks;
-use crate::serde::SupStateSerde;
+use crate::journal::{Journal, PushedRef};
+use crate::pull::{DivergedError, PullStrategy};
 use crate::ui::UI;
 use anyhow::{Context, Result};
 use git2::{ErrorCode, Repository, StashFlags};
 use indicatif::ProgressStyle;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::env;
-use std::fs::OpenOptions;
-use std::fs::{self, File};
-use std::io::Read;
+use std::fs;
 use std::io::Write;
-use std::path::Path;
-use std::process::{self, exit};
+use std::path::{Path, PathBuf};
 use std::str::FromStr as _;
 use tracing::instrument;
 use tracing::Span;
 use tracing::{debug, error, warn};
-use tracing_indicatif::span_ext::IndicatifSpanExt;
 use tracing_indicatif::IndicatifLayer;
-use tracing_subscriber::filter::Targets;
+use tracing_subscriber::filter::{LevelFilter, Targets};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
-const STATE_FILE: &str = ".git/sup_state";
-const LOCK_FILE: &str = ".git/sup.lock";
+const STATE_FILE: &str = "sup_state";
+
+/// Message of stash which keeps changes that were not committed while push is retried
+const UNCOMMITTED_STASH_MESSAGE: &str = "sup stash of uncommitted changes";
+
+/// Path of a sup file in git directory, which is separate for each linked worktree
+pub(crate) fn git_file(repo: &Repository, name: &str) -> PathBuf {
+    repo.path().join(name)
+}
+
+/// Open repository containing current directory, which could be a subdirectory
+/// of the working tree or a linked worktree
+pub(crate) fn open_repo() -> Result<Repository> {
+    let repo = Repository::discover(".").context("failed to open git repository")?;
+    if repo.is_bare() {
+        anyhow::bail!("sup needs a working tree, but repository is bare");
+    }
+    Ok(repo)
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
-#[serde(
-    from = "SupStateSerde",
-    into = "SupStateSerde",
-    rename_all = "snake_case"
-)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum SupState {
     Idle,
     InProgress {
         stash_created: bool,
         original_head: String,
         message: Option<String>,
+        stash_oid: Option<String>,
+        /// paths to commit, relative to the root of working tree, empty to commit all changes
+        #[serde(default)]
+        paths: Vec<String>,
+        /// whether only staged changes are committed
+        #[serde(default)]
+        staged: bool,
     },
     Interrupted {
         stash_created: bool,
         stash_applied: bool,
         original_head: String,
         message: Option<String>,
+        rebasing: bool,
+        stash_oid: Option<String>,
+        #[serde(default)]
+        paths: Vec<String>,
+        #[serde(default)]
+        staged: bool,
+        /// command which has to succeed before push
+        #[serde(default)]
+        check: Option<String>,
+        /// whether changes were committed, so only check and push are left
+        #[serde(default)]
+        committed: bool,
     },
 }
 
 impl SupState {
-    fn load() -> Result<Self> {
-        let path = Path::new(STATE_FILE);
+    /// State file, in git directory of current worktree
+    pub(crate) fn path(repo: &Repository) -> PathBuf {
+        git_file(repo, STATE_FILE)
+    }
+    pub(crate) fn load(repo: &Repository) -> Result<Self> {
+        let path = Self::path(repo);
         if !path.exists() {
             return Ok(SupState::Idle);
         }
-        let mut file = File::open(path)?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
-        let state: SupState = serde_json::from_str(&buf)?;
-        Ok(state)
+        let buf = fs::read_to_string(&path)?;
+        crate::serde::from_str(&path, &buf)
     }
-    fn save(&self) -> Result<()> {
-        let mut file = File::create(STATE_FILE)?;
-        let state_str = serde_json::to_string(self)?;
-        file.write_all(state_str.as_bytes())?;
+    /// Write state to temporary file and rename it over the state file,
+    /// so that interrupted write does not leave a truncated state behind
+    fn save(&self, repo: &Repository) -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new_in(repo.path())?;
+        file.write_all(crate::serde::to_string(self)?.as_bytes())?;
+        file.as_file().sync_all()?;
+        file.persist(Self::path(repo))?;
         Ok(())
     }
-    fn clear() -> Result<()> {
-        if Path::new(STATE_FILE).exists() {
-            fs::remove_file(STATE_FILE)?;
+    fn clear(repo: &Repository) -> Result<()> {
+        let path = Self::path(repo);
+        if path.exists() {
+            fs::remove_file(path)?;
         }
         Ok(())
     }
 }
 
-pub fn run_sup(
-    r#continue: bool,
-    abort: bool,
-    version: bool,
-    message: Option<String>,
-    yes: bool,
-    no_verify: bool,
-) -> Result<()> {
-    if version {
-        println!("sup version {}", env!("CARGO_PKG_VERSION"));
-        process::exit(0);
-    }
-    let indicatif_layer = IndicatifLayer::new().with_progress_style(
-        ProgressStyle::with_template("{elapsed:>4.bold.dim} {spinner:.green} {wide_msg}  ")
-            .expect("Failed to parse progress style"),
-    );
+/// Set up logging with progress bars, filtered by RUST_LOG
+pub(crate) fn init_tracing() {
+    let default_level = match crate::output::verbosity() {
+        crate::output::Verbosity::Quiet => LevelFilter::ERROR,
+        crate::output::Verbosity::Normal => tracing_subscriber::FmtSubscriber::DEFAULT_MAX_LEVEL,
+        crate::output::Verbosity::Verbose => LevelFilter::DEBUG,
+    };
     let targets = match env::var("RUST_LOG") {
         Ok(var) => Targets::from_str(&var)
             .map_err(|e| {
                 eprintln!("Ignoring `RUST_LOG={var:?}`: {e}");
             })
             .unwrap_or_default(),
-        Err(env::VarError::NotPresent) => {
-            Targets::new().with_default(tracing_subscriber::FmtSubscriber::DEFAULT_MAX_LEVEL)
-        }
+        Err(env::VarError::NotPresent) => Targets::new().with_default(default_level),
         Err(e) => {
             eprintln!("Ignoring `RUST_LOG`: {e}");
-            Targets::new().with_default(tracing_subscriber::FmtSubscriber::DEFAULT_MAX_LEVEL)
+            Targets::new().with_default(default_level)
         }
     };
 
+    let fmt_layer = tracing_subscriber::fmt::layer().with_ansi(crate::output::colored_logs());
+    if !crate::output::shows_progress() {
+        // steps are printed line by line or as JSON events instead, logs go to stderr
+        tracing_subscriber::registry()
+            .with(fmt_layer.with_writer(std::io::stderr))
+            .with(crate::ui::PlainLayer)
+            .with(targets)
+            .init();
+        return;
+    }
+    let indicatif_layer = IndicatifLayer::new().with_progress_style(
+        ProgressStyle::with_template("{elapsed:>4.bold.dim} {spinner:.green} {wide_msg}  ")
+            .expect("Failed to parse progress style"),
+    );
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_writer(indicatif_layer.get_stderr_writer()))
+        .with(fmt_layer.with_writer(indicatif_layer.get_stderr_writer()))
         .with(indicatif_layer)
         .with(targets)
         .init();
+}
 
-    // Acquire lock file to prevent concurrent sup runs
-    let lock_path = Path::new(LOCK_FILE);
-    let _lock_file = match OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(lock_path)
-    {
-        Ok(f) => f,
-        Err(e) => {
+/// Options of `sup sync` and `sup continue`, as given on command line
+#[derive(Debug, Default)]
+pub(crate) struct RunOptions {
+    /// commit message for restored local changes, which are not committed without it
+    pub(crate) message: Option<String>,
+    pub(crate) staged: bool,
+    pub(crate) interactive: bool,
+    /// pathspecs to commit, relative to current directory
+    pub(crate) paths: Vec<String>,
+    pub(crate) dry_run: bool,
+    pub(crate) rebase: bool,
+    pub(crate) ff_only: bool,
+    pub(crate) yes: bool,
+    pub(crate) no_verify: bool,
+    pub(crate) push_retries: Option<u32>,
+    pub(crate) keep_backups: Option<usize>,
+    pub(crate) check: Option<String>,
+    pub(crate) gpg_sign: bool,
+    pub(crate) no_gpg_sign: bool,
+    pub(crate) force_unlock: bool,
+}
+
+impl RunOptions {
+    /// Reject options which make no sense together
+    fn validate(&self) -> Result<()> {
+        if self.rebase && self.ff_only {
+            anyhow::bail!("--rebase and --ff-only cannot be used together");
+        }
+        if self.gpg_sign && self.no_gpg_sign {
+            anyhow::bail!("--gpg-sign and --no-gpg-sign cannot be used together");
+        }
+        if self.message.is_none() {
+            if self.staged {
+                anyhow::bail!("--staged can only be used together with --message");
+            }
+            if self.interactive || !self.paths.is_empty() {
+                anyhow::bail!("Choosing paths to commit can only be used together with --message");
+            }
+            if self.check.is_some() {
+                anyhow::bail!("--check can only be used together with --message");
+            }
+        }
+        if self.dry_run && self.force_unlock {
             anyhow::bail!(
-                "Another sup process is running, could not take a lock {}: {}. Aborting.",
-                LOCK_FILE,
-                e
-            )
+                "--dry-run does not take the lock, so --force-unlock cannot be used with it"
+            );
         }
-    };
-    // Ensure lock file is removed at the end (even on panic)
-    struct LockGuard<'a> {
-        path: &'a Path,
+        Ok(())
     }
-    impl Drop for LockGuard<'_> {
-        fn drop(&mut self) {
-            let _ = std::fs::remove_file(self.path);
+
+    /// Settings given as flags, which take precedence over configured ones
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = Vec::new();
+        if self.rebase {
+            settings.push(("pullStrategy", "rebase".to_string()));
+        }
+        if self.ff_only {
+            settings.push(("pullStrategy", "ff-only".to_string()));
+        }
+        if self.yes {
+            settings.push(("autoConfirmDropStash", "true".to_string()));
+        }
+        if self.no_verify {
+            settings.push(("noVerify", "true".to_string()));
         }
+        if let Some(push_retries) = self.push_retries {
+            settings.push(("pushRetries", push_retries.to_string()));
+        }
+        if let Some(keep_backups) = self.keep_backups {
+            settings.push(("keepBackups", keep_backups.to_string()));
+        }
+        if self.gpg_sign || self.no_gpg_sign {
+            settings.push(("gpgSign", self.gpg_sign.to_string()));
+        }
+        if let Some(ref check) = self.check {
+            settings.push(("checkCommand", check.clone()));
+        }
+        settings
     }
-    let _lock_guard = LockGuard { path: lock_path };
+}
 
-    ctrlc::set_handler(move || {
-        let _ = std::fs::remove_file(lock_path);
-        exit(1)
-    })?;
+/// What a run records in its state to restore local changes and commit them
+struct Run<'a> {
+    stash_created: bool,
+    stash_oid: Option<&'a str>,
+    original_head: &'a str,
+    message: &'a Option<String>,
+    paths: &'a [String],
+    staged: bool,
+    check: Option<&'a str>,
+}
 
-    let mut state = SupState::load()?;
-    if abort {
-        let state_for_abort = state;
-        match state_for_abort {
-            SupState::Interrupted {
-                stash_created,
-                original_head,
-                ..
-            } => {
-                let mut ui = UI::new();
-                ui.log_abort();
-                let mut repo = Repository::open(".").context("failed to open git repository")?;
-                reset_repo(&mut ui, &mut repo, &original_head)?;
-
-                // Restore stashed changes if any
-                if stash_created {
-                    let mut repo =
-                        Repository::open(".").context("failed to open git repository")?;
-                    pop_stash(&mut ui, &mut repo);
-                }
-                ui.log_completed();
-            }
-            _ => {
-                anyhow::bail!("No interrupted operation to abort");
-            }
+impl Run<'_> {
+    fn interrupted(&self, stash_applied: bool, rebasing: bool) -> SupState {
+        SupState::Interrupted {
+            stash_created: self.stash_created,
+            original_head: self.original_head.to_string(),
+            message: self.message.clone(),
+            stash_applied,
+            rebasing,
+            stash_oid: self.stash_oid.map(str::to_string),
+            paths: self.paths.to_vec(),
+            staged: self.staged,
+            check: self.check.map(str::to_string),
+            committed: false,
         }
-        SupState::clear()?;
-        return Ok(());
     }
-    if r#continue {
-        match state {
-            SupState::Interrupted {
-                stash_created,
-                original_head,
-                message,
-                stash_applied,
-            } => {
-                let mut ui = UI::new();
-                ui.log_continuing_interrupted_operation();
-
-                // 1. If a merge is in progress, finish it (assume user resolved conflicts and staged files)
-                let mut repo = Repository::open(".").context("failed to open git repository")?;
-                if repo.state() == git2::RepositoryState::Merge {
-                    merge_repo(&mut ui, &mut repo)?;
-                }
 
-                // 2. Apply stash if it was created
-                if stash_created {
-                    apply_stash_and_commit(
-                        &mut repo,
-                        stash_created,
-                        stash_applied,
-                        &original_head,
-                        &message,
-                        &mut ui,
-                        yes,
-                        no_verify,
-                    )?;
-                }
-                SupState::clear()?;
-                ui.log_completed();
-                return Ok(());
-            }
-            _ => {
-                anyhow::bail!("No interrupted operation to continue, {:?}", state);
-            }
+    /// State after changes were committed but not pushed, because check has failed or upstream
+    /// changes could not be integrated, where stash is kept until commit is pushed,
+    /// so that abort can still restore local changes
+    fn not_pushed(&self, rebasing: bool) -> SupState {
+        let mut state = self.interrupted(true, rebasing);
+        if let SupState::Interrupted {
+            ref mut committed, ..
+        } = state
+        {
+            *committed = true;
         }
+        state
     }
-    if let SupState::InProgress { .. } = state {
-        anyhow::bail!("Operation already in progress. To roll back, run with --abort. To continue, run with --continue.");
+}
+
+/// `sup sync`: stash local changes, pull, bring changes back, then commit and push them
+/// when message is given
+pub fn run_sync(options: RunOptions) -> Result<()> {
+    options.validate()?;
+    let settings = options.settings();
+    if options.dry_run {
+        return crate::preview::run_preview(&settings, options.message.as_deref());
+    }
+    init_tracing();
+    let mut repo = open_repo()?;
+    let config = Config::load(&repo, &settings)?;
+    let _lock_guard = crate::lock::lock(&repo, options.force_unlock)?;
+
+    match SupState::load(&repo)? {
+        SupState::Idle => {}
+        // lock is held, so no other sup is running that operation anymore
+        SupState::InProgress { .. } => anyhow::bail!(
+            "Previous operation did not finish: {}",
+            crate::status::stopped_hint(&repo)
+        ),
+        SupState::Interrupted { .. } => anyhow::bail!(
+            "Operation was interrupted. Run `sup status` to see what happened, \
+            then `sup continue` to finish it or `sup abort` to roll it back."
+        ),
     }
 
     let mut ui = UI::new();
-    let mut repo = Repository::open(".").context("failed to open git repository")?;
 
-    let stash_created = stash_changes(&mut ui, &mut repo)?;
+    let strategy = pull_strategy(&repo, &config)?;
+    debug!("Using pull strategy {:?}", strategy);
+    // fail early, before any changes are made, if there is nothing to pull from
+    let source = crate::upstream::pull_source(&repo, config.remote.as_deref(), None)?;
+    debug!("Will pull {} from {}", source.merge, source.remote);
+    let staged = options.staged;
+    let mut paths = crate::select::repo_relative(&repo, &options.paths)?;
+    if staged || options.interactive || !paths.is_empty() {
+        let files = crate::select::changed_files(&repo, staged, &paths)?;
+        if files.is_empty() {
+            if staged {
+                anyhow::bail!(
+                    "Nothing is staged to commit, stage changes with `git add` or run without --staged"
+                );
+            }
+            if paths.is_empty() {
+                // only --interactive was given, so there is nothing to choose from
+                anyhow::bail!("No changes selected to commit");
+            }
+            anyhow::bail!("No changes to commit in {}", paths.join(" "));
+        }
+        if options.interactive {
+            paths = crate::select::choose_interactively(&files)?;
+        }
+    }
 
-    let mut pulling = crate::pull::Pulling {};
+    let backup = if config.keep_backups > 0 {
+        Some(crate::backup::Backup::create(&repo)?)
+    } else {
+        None
+    };
+    let stash_oid = stash_changes(&mut ui, &mut repo)?;
+    let stash_created = stash_oid.is_some();
+    if let Some(ref backup) = backup {
+        if let Some(ref stash_oid) = stash_oid {
+            backup.record_stash(&repo, stash_oid)?;
+        }
+        crate::backup::prune(&repo, config.keep_backups)?;
+    }
+    Journal::start(&repo, stash_oid.as_deref())?;
 
-    let original_head = pull_changes(&mut repo, &mut pulling, &mut ui, stash_created, &message)?;
+    let mut pulling = crate::pull::Pulling {
+        strategy,
+        remote: config.remote.clone(),
+        gpg_sign: config.gpg_sign,
+        no_verify: config.no_verify,
+    };
+
+    let original_head = match repo.head()?.target() {
+        Some(id) => Ok(id.to_string()),
+        None => Err(anyhow::format_err!(
+            "Cannot determine original head for pull"
+        )),
+    }?;
+    let run = Run {
+        stash_created,
+        stash_oid: stash_oid.as_deref(),
+        original_head: &original_head,
+        message: &options.message,
+        paths: &paths,
+        staged,
+        check: config.check_command.as_deref(),
+    };
+    pull_changes(&mut repo, &mut pulling, &mut ui, &config, &run)?;
+    let upstream = repo.refname_to_id(&source.tracking_ref())?;
+    if let Some(summary) =
+        crate::summary::pull_summary(&repo, &original_head, upstream, &config.watch)?
+    {
+        ui.log_pull_summary(&summary);
+    }
 
     debug!("Checking out the head with force");
     // checking out the head to ensure that index and working directory are clean
     checking_out_with_force(&repo)?;
     // repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
-    state = SupState::InProgress {
+    SupState::InProgress {
         stash_created,
         original_head: original_head.clone(),
-        message: message.clone(),
+        message: options.message.clone(),
+        stash_oid: stash_oid.clone(),
+        paths: paths.clone(),
+        staged,
+    }
+    .save(&repo)?;
+
+    if stash_created {
+        apply_stash_and_commit(&mut repo, &mut pulling, &mut ui, &config, &run, false)?;
+    }
+    SupState::clear(&repo)?;
+    Journal::finish(&repo)?;
+    ui.log_completed();
+    // LockGuard will remove the lock file here
+    Ok(())
+}
+
+/// `sup continue`: finish interrupted run after conflicts were resolved,
+/// committing and pushing with the message it was started with
+pub fn run_continue(options: RunOptions) -> Result<()> {
+    options.validate()?;
+    init_tracing();
+    let mut repo = open_repo()?;
+    let config = Config::load(&repo, &options.settings())?;
+    let _lock_guard = crate::lock::lock(&repo, options.force_unlock)?;
+
+    let state = SupState::load(&repo)?;
+    let SupState::Interrupted {
+        stash_created,
+        original_head,
+        message,
+        stash_applied,
+        rebasing,
+        stash_oid,
+        paths,
+        staged,
+        check,
+        committed,
+    } = state
+    else {
+        anyhow::bail!("No interrupted operation to continue, {:?}", state);
+    };
+    let mut ui = UI::new();
+    ui.log_continuing_interrupted_operation();
+    let run = Run {
+        stash_created,
+        stash_oid: stash_oid.as_deref(),
+        original_head: &original_head,
+        message: &message,
+        paths: &paths,
+        staged,
+        check: check.as_deref(),
     };
-    state.save()?;
 
+    // 1. If a merge is in progress, finish it (assume user resolved conflicts and staged files)
+    if repo.state() == git2::RepositoryState::Merge {
+        merge_repo(&mut ui, &mut repo, config.gpg_sign)?;
+    }
+    // or if sup was rebasing, replay the rest of local commits
+    if rebasing && is_rebase_in_progress(&repo) {
+        let mut pulling = crate::pull::Pulling {
+            strategy: PullStrategy::Rebase,
+            remote: None,
+            gpg_sign: config.gpg_sign,
+            no_verify: config.no_verify,
+        };
+        if let Err(e) = pulling.continue_rebase(&repo) {
+            error!("Failed to continue rebase: {}", e);
+            if check_conflicts(&repo)? {
+                let message = format!("Cannot continue rebase: {e}");
+                return Err(conflict_error(&repo, "rebase", message)?);
+            }
+            anyhow::bail!("Cannot continue rebase: {e}");
+        }
+    }
+
+    // changes were committed, but check failed or push could not be retried, so push again
+    if committed {
+        restore_uncommitted_changes(&mut repo)?;
+        let mut pulling = crate::pull::Pulling {
+            strategy: pull_strategy(&repo, &config)?,
+            remote: config.remote.clone(),
+            gpg_sign: config.gpg_sign,
+            no_verify: config.no_verify,
+        };
+        check_and_push(&mut repo, &mut pulling, &mut ui, &config, &run)?;
+        if stash_created {
+            // stashed changes are in the pushed commit now
+            debug!("Dropping stash entry after changes were pushed");
+            let stash_index = find_sup_stash(&mut repo, run.stash_oid)?;
+            repo.stash_drop(stash_index)?;
+        }
+        SupState::clear(&repo)?;
+        Journal::finish(&repo)?;
+        ui.log_completed();
+        return Ok(());
+    }
+
+    // 2. Apply stash if it was created
     if stash_created {
+        let mut pulling = crate::pull::Pulling {
+            strategy: pull_strategy(&repo, &config)?,
+            remote: config.remote.clone(),
+            gpg_sign: config.gpg_sign,
+            no_verify: config.no_verify,
+        };
         apply_stash_and_commit(
             &mut repo,
-            stash_created,
-            false,
-            &original_head,
-            &message,
+            &mut pulling,
             &mut ui,
-            yes,
-            no_verify,
+            &config,
+            &run,
+            stash_applied,
         )?;
     }
-    SupState::clear()?;
+    SupState::clear(&repo)?;
+    Journal::finish(&repo)?;
     ui.log_completed();
-    // LockGuard will remove the lock file here
+    Ok(())
+}
+
+/// `sup abort`: roll back interrupted run, restoring original HEAD and local changes
+pub fn run_abort(force_unlock: bool) -> Result<()> {
+    init_tracing();
+    let mut repo = open_repo()?;
+    let _lock_guard = crate::lock::lock(&repo, force_unlock)?;
+
+    let SupState::Interrupted {
+        stash_created,
+        original_head,
+        rebasing,
+        stash_oid,
+        committed,
+        ..
+    } = SupState::load(&repo)?
+    else {
+        anyhow::bail!("No interrupted operation to abort");
+    };
+    let mut ui = UI::new();
+    ui.log_abort();
+    if rebasing && is_rebase_in_progress(&repo) {
+        let mut pulling = crate::pull::Pulling {
+            strategy: PullStrategy::Rebase,
+            remote: None,
+            gpg_sign: None,
+            no_verify: false,
+        };
+        pulling.abort_rebase(&repo)?;
+    }
+    reset_repo(&mut ui, &mut repo, &original_head)?;
+    if committed && stash_created {
+        // uncommitted changes put aside for push retry are in sup stash too
+        if let Some(stash_index) = find_uncommitted_stash(&mut repo)? {
+            repo.stash_drop(stash_index)?;
+        }
+    }
+
+    // Restore stashed changes if any
+    if stash_created {
+        let mut repo = Repository::open(repo.path()).context("failed to open git repository")?;
+        pop_stash(&mut ui, &mut repo, stash_oid.as_deref())?;
+    }
+    ui.log_completed();
+    SupState::clear(&repo)?;
+    Journal::clear(&repo)?;
     Ok(())
 }
 
 #[instrument(skip_all)]
 fn checking_out_with_force(repo: &Repository) -> Result<()> {
-    Span::current().pb_set_message("Checking out HEAD with force");
+    crate::ui::step(
+        &Span::current(),
+        "checking_out",
+        "Checking out HEAD with force",
+        "",
+    );
     // checking out the head to ensure that index and working directory are clean
     repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
     Ok(())
 }
 
 #[instrument(skip_all)]
-fn merge_repo(ui: &mut UI, repo: &mut Repository) -> Result<()> {
+fn merge_repo(ui: &mut UI, repo: &mut Repository, gpg_sign: Option<bool>) -> Result<()> {
     ui.configure_finishing_merge_progress(&Span::current());
 
     // Try to create a merge commit if index is not conflicted
@@ -274,10 +574,10 @@ fn merge_repo(ui: &mut UI, repo: &mut Repository) -> Result<()> {
     let tree_id = index.write_tree()?;
     let tree = repo.find_tree(tree_id)?;
     let head_commit = repo.head()?.peel_to_commit()?;
-    // Read .git/MERGE_HEAD to get merge parent OIDs
-    let merge_head_path = Path::new(".git/MERGE_HEAD");
+    // Read MERGE_HEAD to get merge parent OIDs
+    let merge_head_path = repo.path().join("MERGE_HEAD");
     let merge_head_content =
-        std::fs::read_to_string(merge_head_path).context("Failed to read .git/MERGE_HEAD")?;
+        std::fs::read_to_string(merge_head_path).context("Failed to read MERGE_HEAD")?;
     // Collect parent commits as owned values
     let mut parent_commits = Vec::new();
     parent_commits.push(head_commit);
@@ -292,42 +592,69 @@ fn merge_repo(ui: &mut UI, repo: &mut Repository) -> Result<()> {
     // Build refs vector for commit
     let parent_refs: Vec<&git2::Commit> = parent_commits.iter().collect();
     let msg = "Merge commit (sup --continue)";
-    repo.commit(Some("HEAD"), &sig, &sig, msg, &tree, &parent_refs)?;
+    crate::sign::commit(repo, &sig, &sig, msg, &tree, &parent_refs, gpg_sign)?;
     repo.cleanup_state()?;
+    hooks::run_post_hook(repo, "post-commit", &[]);
     debug!("Merge commit created and merge state cleaned up");
     Ok(())
 }
 
 #[instrument(skip_all)]
-fn pop_stash(ui: &mut UI, repo: &mut Repository) {
-    // Only pop the stash created by sup (with message 'sup stash')
+fn pop_stash(ui: &mut UI, repo: &mut Repository, stash_oid: Option<&str>) -> Result<()> {
+    // Only pop the stash created by sup
     ui.configure_restoring_stashed_changes_for_abort_progress(&Span::current());
+    let stash_index = find_sup_stash(repo, stash_oid)?;
+    unstash(repo, stash_index, true).context("Failed to apply sup stash during abort")?;
+    debug!("sup stash applied during abort");
+    Ok(())
+}
+
+/// Find position of the stash created by sup in the stash list,
+/// which could have changed if anything else was stashed meanwhile.
+pub(crate) fn find_sup_stash(repo: &mut Repository, stash_oid: Option<&str>) -> Result<usize> {
     let mut sup_stash_index: Option<usize> = None;
-    let mut idx = 0;
-    let _ = repo.stash_foreach(|stash_index, stash_msg, _| {
-        if stash_index > 0 {
-            return false; // only last stash can be correct sup stash
-        }
-        if stash_msg.ends_with("sup stash") {
-            sup_stash_index = Some(stash_index);
-            return false; // stop after finding
-        }
-        warn!("Ignoring unrecognized stash {}: {}", idx, stash_msg);
-        idx += 1;
-        true
-    });
-    if let Some(stash_index) = sup_stash_index {
-        match repo.stash_pop(stash_index, None) {
-            Ok(_) => debug!("sup stash applied during abort"),
-            Err(e) => error!("Failed to apply sup stash during abort: {}", e),
+    match stash_oid {
+        Some(stash_oid) => {
+            let stash_oid = git2::Oid::from_str(stash_oid)?;
+            repo.stash_foreach(|stash_index, _, oid| {
+                if *oid == stash_oid {
+                    sup_stash_index = Some(stash_index);
+                    return false; // stop after finding
+                }
+                true
+            })?;
+            match sup_stash_index {
+                Some(stash_index) => {
+                    debug!("Found sup stash {} at index {}", stash_oid, stash_index);
+                    Ok(stash_index)
+                }
+                None => anyhow::bail!(
+                    "sup stash {stash_oid} is not in the stash list anymore, refusing to continue. \
+                    If it was applied or dropped manually, recover it with `git stash apply {stash_oid}` if needed \
+                    and remove {}",
+                    SupState::path(repo).display()
+                ),
+            }
+        }
+        None => {
+            // state saved by older version, where only the last stash could be the sup stash
+            repo.stash_foreach(|stash_index, stash_msg, _| {
+                if stash_msg.ends_with("sup stash") {
+                    sup_stash_index = Some(stash_index);
+                } else {
+                    warn!("Ignoring unrecognized stash {}: {}", stash_index, stash_msg);
+                }
+                false
+            })?;
+            sup_stash_index.ok_or_else(|| {
+                anyhow::anyhow!("No sup stash found on top of stash list, refusing to continue")
+            })
         }
-    } else {
-        warn!("No sup stash found to apply during abort; likely already popped or not created");
     }
 }
 
 #[instrument(skip_all)]
-fn reset_repo(ui: &mut UI, repo: &mut Repository, orig_head: &str) -> Result<()> {
+pub(crate) fn reset_repo(ui: &mut UI, repo: &mut Repository, orig_head: &str) -> Result<()> {
     ui.configure_resetting_progress(&Span::current(), orig_head);
     repo.reset(
         &repo.find_object(
@@ -340,9 +667,59 @@ fn reset_repo(ui: &mut UI, repo: &mut Repository, orig_head: &str) -> Result<()>
     Ok(())
 }
 
+/// Pick pull strategy from sup settings, falling back to git config of `git pull`
+pub(crate) fn pull_strategy(repo: &Repository, config: &Config) -> Result<PullStrategy> {
+    if let Some(strategy) = config.pull_strategy {
+        return Ok(strategy);
+    }
+    let head = repo.head()?;
+    let branch = if head.is_branch() {
+        head.shorthand()
+    } else {
+        None
+    };
+    Ok(PullStrategy::from_config(repo, branch)?)
+}
+
+fn is_rebase_in_progress(repo: &Repository) -> bool {
+    matches!(
+        repo.state(),
+        git2::RepositoryState::Rebase
+            | git2::RepositoryState::RebaseMerge
+            | git2::RepositoryState::RebaseInteractive
+    )
+}
+
+/// Returned when sup stops on conflicts, which user has to resolve before `sup continue`
+#[derive(Debug)]
+pub(crate) struct ConflictError {
+    pub(crate) message: String,
+    pub(crate) files: Vec<String>,
+}
+
+impl std::fmt::Display for ConflictError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ConflictError {}
+
+/// Report conflicts left in working tree by `stage` of the run
+fn conflict_error(repo: &Repository, stage: &str, message: String) -> Result<anyhow::Error> {
+    let files = crate::status::conflicted_files(repo)?;
+    crate::output::event("conflicts", json!({ "stage": stage, "files": files }));
+    Ok(ConflictError { message, files }.into())
+}
+
 #[instrument(skip_all)]
 fn check_conflicts(repo: &Repository) -> Result<bool> {
-    Span::current().pb_set_message("Checking for conflicts");
+    crate::ui::step(
+        &Span::current(),
+        "checking_conflicts",
+        "Checking for conflicts",
+        "",
+    );
     let statuses = repo.statuses(None)?;
     for entry in statuses.iter() {
         if entry.status().is_conflicted() {
@@ -357,96 +734,109 @@ fn pull_changes(
     repo: &mut Repository,
     pulling: &mut crate::pull::Pulling,
     ui: &mut UI,
-    stash_created: bool,
-    message: &Option<String>,
-) -> Result<String> {
+    config: &Config,
+    run: &Run,
+) -> Result<()> {
     ui.configure_pulling_progress(&Span::current());
-    let original_head = match repo.head()?.target() {
-        Some(id) => Ok(id.to_string()),
-        None => Err(anyhow::format_err!(
-            "Cannot determine original head for pull"
-        )),
-    }?;
 
-    if std::env::var("PULL_WITH_CLI").is_ok() {
-        let status = std::process::Command::new("git").arg("pull").status()?;
+    if config.pull_with_cli {
+        let mut command = std::process::Command::new("git");
+        command.arg("pull");
+        // current directory could have been removed by stash if it was untracked
+        if let Some(workdir) = repo.workdir() {
+            command.current_dir(workdir);
+        }
+        if crate::output::is_json() {
+            // stdout is reserved for JSON events
+            command.stdout(std::io::stderr());
+        }
+        match pulling.strategy {
+            PullStrategy::Merge => {}
+            PullStrategy::Rebase => {
+                command.arg("--rebase");
+            }
+            PullStrategy::FastForwardOnly => {
+                command.arg("--ff-only");
+            }
+        }
+        match pulling.gpg_sign {
+            Some(true) => {
+                command.arg("--gpg-sign");
+            }
+            Some(false) => {
+                command.arg("--no-gpg-sign");
+            }
+            None => {}
+        }
+        if let Some(ref remote) = config.remote {
+            // without the branch git would only accept the remote of current branch upstream
+            let source = crate::upstream::pull_source(repo, Some(remote), None)?;
+            command.arg(&source.remote).arg(source.branch());
+        }
+        let status = command.status()?;
         if !status.success() {
             error!("git pull failed");
-            SupState::Interrupted {
-                stash_created,
-                original_head,
-                message: message.clone(),
-                stash_applied: false,
+            run.interrupted(false, is_rebase_in_progress(repo))
+                .save(repo)?;
+            if check_conflicts(repo)? {
+                return Err(conflict_error(repo, "pull", "git pull failed".to_string())?);
             }
-            .save()?;
             anyhow::bail!("git pull failed");
         }
     } else {
-        // Determine current branch
-        let head = repo.head()?;
-        let branch = if head.is_branch() {
-            head.shorthand().map(|s| s.to_string())
-        } else {
-            None
-        };
-        // Determine remote for current branch
-        let remote = if let Some(ref branch_name) = branch {
-            let branch_ref = repo.find_branch(branch_name, git2::BranchType::Local)?;
-            branch_ref.upstream().ok().and_then(|up| {
-                match up.name() {
-                    Ok(Some(name)) => {
-                        // name is like "refs/remotes/origin/master"
-                        let parts: Vec<&str> = name.split('/').collect();
-                        if parts.len() >= 3 {
-                            Some(parts[2].to_string())
-                        } else {
-                            None
-                        }
-                    }
-                    _ => None,
+        let args = crate::pull::Args::default();
+        if let Err(e) = pulling.pull_run(repo, &args) {
+            if e.downcast_ref::<DivergedError>().is_some() || e.is::<hooks::HookFailedError>() {
+                // nothing was changed by pull, so just give back local changes
+                error!("Pull was refused: {}", e);
+                if run.stash_created {
+                    pop_stash(ui, repo, run.stash_oid)?;
                 }
-            })
-        } else {
-            None
-        };
-        let args = crate::pull::Args {
-            arg_remote: remote,
-            arg_branch: branch,
-        };
-        if let Err(e) = pulling.pull_run(&args) {
+                Journal::clear(repo)?;
+                return Err(e);
+            }
             error!("git pull failed: {}", e);
-            SupState::Interrupted {
-                stash_created,
-                original_head,
-                message: message.clone(),
-                stash_applied: false,
+            run.interrupted(false, is_rebase_in_progress(repo))
+                .save(repo)?;
+            if check_conflicts(repo)? {
+                return Err(conflict_error(
+                    repo,
+                    "pull",
+                    format!("git pull failed: {e}"),
+                )?);
             }
-            .save()?;
             anyhow::bail!("git pull failed: {e}");
         }
     }
 
-    Ok(original_head)
+    Ok(())
 }
 
 #[instrument(skip_all)]
-fn stash_changes(ui: &mut UI, repo: &mut Repository) -> Result<bool, anyhow::Error> {
+fn stash_changes(ui: &mut UI, repo: &mut Repository) -> Result<Option<String>, anyhow::Error> {
     ui.configure_stashing_progress(&Span::current());
     let sig = repo.signature()?;
     let stash_result = repo.stash_save(&sig, "sup stash", Some(StashFlags::INCLUDE_UNTRACKED));
-    let stash_created = match stash_result {
-        Ok(_) => true,
+    let stash_oid = match stash_result {
+        Ok(oid) => {
+            debug!("Stashed changes as {}", oid);
+            Some(oid.to_string())
+        }
         Err(ref e) if e.code() == ErrorCode::NotFound => {
             debug!("No changes to stash");
-            false
+            None
         }
         Err(e) => return Err(e.into()),
     };
-    Ok(stash_created)
+    crate::output::event(
+        "stash",
+        json!({ "created": stash_oid.is_some(), "stash_oid": stash_oid }),
+    );
+    Ok(stash_oid)
 }
 
 #[instrument(skip_all)]
-fn apply_stash(repo: &mut Repository, ui: &mut UI) -> Result<()> {
+fn apply_stash(repo: &mut Repository, ui: &mut UI, stash_oid: Option<&str>) -> Result<()> {
     ui.configure_applying_stash_progress(&Span::current());
     // Ensure index is clean before applying stashed changes
     repo.reset(
@@ -455,78 +845,97 @@ fn apply_stash(repo: &mut Repository, ui: &mut UI) -> Result<()> {
         None,
     )?;
     // Use stash_apply and only drop if no conflicts
-    let apply_res = repo.stash_apply(0, None);
-    apply_res?;
+    let stash_index = find_sup_stash(repo, stash_oid)?;
+    unstash(repo, stash_index, false)?;
+    Ok(())
+}
+
+/// Apply stash bringing staged changes back to the index, not only to working tree,
+/// and drop it afterwards when `pop` is set
+pub(crate) fn unstash(repo: &mut Repository, stash_index: usize, pop: bool) -> Result<()> {
+    let mut options = git2::StashApplyOptions::new();
+    options.reinstantiate_index();
+    match repo.stash_apply(stash_index, Some(&mut options)) {
+        Err(e) if e.code() == ErrorCode::Conflict => {
+            // nothing is changed yet when staged changes conflict with the working tree
+            warn!(
+                "Staged changes conflict with changes made meanwhile, restoring them as unstaged: {}",
+                e.message()
+            );
+            repo.stash_apply(stash_index, None)?;
+        }
+        res => res?,
+    }
+    if pop {
+        repo.stash_drop(stash_index)?;
+    }
     Ok(())
 }
 
 fn apply_stash_and_commit(
     repo: &mut Repository,
-    stash_created: bool,
-    stash_applied: bool,
-    original_head: &str,
-    message: &Option<String>,
+    pulling: &mut crate::pull::Pulling,
     ui: &mut UI,
-    yes: bool,
-    no_verify: bool,
+    config: &Config,
+    run: &Run,
+    stash_applied: bool,
 ) -> Result<(), anyhow::Error> {
+    let stash_oid = run.stash_oid;
     if stash_applied {
         let has_conflicts = check_conflicts(repo)?;
         if has_conflicts {
             error!("Conflicts detected before dropping stash");
-            anyhow::bail!("Conflicts detected, cannot continue");
+            let message = "Conflicts detected, cannot continue".to_string();
+            return Err(conflict_error(repo, "continue", message)?);
         }
         // If --message/-m is provided, stage and commit all changes
-        stage_and_commit_with_hooks(repo, message, ui, no_verify)?;
+        stage_and_commit_with_hooks(repo, pulling, ui, config, run)?;
 
-        if yes {
+        if config.auto_confirm_drop_stash {
             debug!("Dropping stash entry since stash was applied previously");
-            repo.stash_drop(0)?;
+            let stash_index = find_sup_stash(repo, stash_oid)?;
+            repo.stash_drop(stash_index)?;
             return Ok(());
         }
         let res = dialoguer::Confirm::new()
             .with_prompt("Stash was already applied, do you want to drop it?")
-            .default(!yes)
+            .default(!config.auto_confirm_drop_stash)
             .interact()?;
         if res {
             debug!("Dropping stash entry since stash was applied previously");
-            repo.stash_drop(0)?;
+            let stash_index = find_sup_stash(repo, stash_oid)?;
+            repo.stash_drop(stash_index)?;
             return Ok(());
         }
         return Ok(());
     }
-    let apply_res = apply_stash(repo, ui);
+    let apply_res = apply_stash(repo, ui, stash_oid);
     match apply_res {
         Ok(_) => {
             debug!("Stash applied, checking for conflicts");
             let has_conflicts = check_conflicts(repo)?;
             if has_conflicts {
                 error!("Conflicts detected after stash apply");
-                SupState::Interrupted {
-                    stash_created,
-                    original_head: original_head.to_string(),
-                    message: message.clone(),
-                    stash_applied: true,
-                }
-                .save()?;
-                anyhow::bail!("Conflicts detected after stash apply");
+                run.interrupted(true, false).save(repo)?;
+                let message = "Conflicts detected after stash apply".to_string();
+                return Err(conflict_error(repo, "stash_apply", message)?);
             } else {
                 debug!("Stash applied successfully with no conflicts");
+                crate::output::event("stash_applied", json!({ "stash_oid": stash_oid }));
                 // If --message/-m is provided, stage and commit all changes
-                stage_and_commit_with_hooks(repo, message, ui, no_verify)?;
+                stage_and_commit_with_hooks(repo, pulling, ui, config, run)?;
                 debug!("Dropping stash entry after successful apply");
-                repo.stash_drop(0)?;
+                let stash_index = find_sup_stash(repo, stash_oid)?;
+                repo.stash_drop(stash_index)?;
             }
         }
         Err(e) => {
             error!("Failed to apply stash: {}", e);
-            SupState::Interrupted {
-                stash_created,
-                original_head: original_head.to_string(),
-                message: message.clone(),
-                stash_applied: true,
+            run.interrupted(true, false).save(repo)?;
+            if check_conflicts(repo)? {
+                let message = format!("Failed to apply stash: {e}");
+                return Err(conflict_error(repo, "stash_apply", message)?);
             }
-            .save()?;
             anyhow::bail!("Failed to apply stash");
         }
     };
@@ -538,88 +947,439 @@ fn commit_stashed_changes(
     ui: &mut UI,
     repo: &Repository,
     msg: &str,
+    paths: &[String],
     no_verify: bool,
+    staged: bool,
+    gpg_sign: Option<bool>,
 ) -> Result<()> {
     ui.configure_committing_stashed_changes_progress_bar(&Span::current());
+    let sig = repo.signature()?;
+    let author_env = hooks::author_env(&sig);
     if !no_verify {
         // --no-verify skips pre-commit hook
         // Run pre-commit hook if present, must suspend progress bar
-        if let Err(e) = hooks::run_hook(repo, "pre-commit", &[]) {
+        if let Err(e) = hooks::run_hook_with_env(repo, "pre-commit", &[], &author_env) {
             error!("pre-commit hook failed: {}", e);
-            SupState::Idle.save()?;
+            SupState::Idle.save(repo)?;
             return Err(e);
         }
     }
-    // Prepare commit message file for commit-msg hook
-    let mut commit_msg_file = tempfile::NamedTempFile::new()?;
-    commit_msg_file.write_all(msg.as_bytes())?;
-    let commit_msg_path = commit_msg_file.path().to_str().unwrap();
-    if let Err(e) = hooks::run_hook(repo, "commit-msg", &[commit_msg_path]) {
-        error!("commit-msg hook failed: {}", e);
-        SupState::Idle.save()?;
+    // Prepare commit message file for prepare-commit-msg and commit-msg hooks,
+    // in .git/COMMIT_EDITMSG like git does
+    let commit_msg_path = repo.path().join("COMMIT_EDITMSG");
+    std::fs::write(&commit_msg_path, msg)?;
+    let commit_msg_path = commit_msg_path
+        .to_str()
+        .context("Invalid git directory path")?;
+    // message is given on command line, which git tells the hook with `message` source
+    let hook_args = [commit_msg_path, "message"];
+    if let Err(e) = hooks::run_hook_with_env(repo, "prepare-commit-msg", &hook_args, &author_env) {
+        error!("prepare-commit-msg hook failed: {}", e);
+        SupState::Idle.save(repo)?;
         return Err(e);
     }
+    if !no_verify {
+        // --no-verify skips commit-msg hook too, as with git commit
+        if let Err(e) =
+            hooks::run_hook_with_env(repo, "commit-msg", &[commit_msg_path], &author_env)
+        {
+            error!("commit-msg hook failed: {}", e);
+            SupState::Idle.save(repo)?;
+            return Err(e);
+        }
+    }
+    // hooks may have edited the message, like adding Change-Id or sign-off
+    let msg = match crate::message::read(repo, Path::new(commit_msg_path)) {
+        Ok(msg) => msg,
+        Err(e) => {
+            SupState::Idle.save(repo)?;
+            return Err(e);
+        }
+    };
+    let msg = msg.as_str();
 
     let mut index = repo.index()?;
-    index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
-    index.write()?;
-    let tree_id = index.write_tree()?;
-    let tree = repo.find_tree(tree_id)?;
-    let sig = repo.signature()?;
+    // hooks may have staged changes, like formatters run by pre-commit do
+    index.read(false)?;
+    if !staged {
+        if paths.is_empty() {
+            index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
+        } else {
+            index.add_all(paths.iter(), git2::IndexAddOption::DEFAULT, None)?;
+        }
+        index.write()?;
+    }
     let parent_commit = repo.head()?.peel_to_commit()?;
-    repo.commit(Some("HEAD"), &sig, &sig, msg, &tree, &[&parent_commit])?;
+    let tree_id = if paths.is_empty() {
+        index.write_tree()?
+    } else {
+        // changes outside of chosen paths are not committed, even if staged
+        crate::select::tree_with_paths(repo, &index, &parent_commit.tree()?, paths)?
+    };
+    let tree = repo.find_tree(tree_id)?;
+    if (staged || !paths.is_empty()) && tree_id == parent_commit.tree_id() {
+        SupState::Idle.save(repo)?;
+        anyhow::bail!("Nothing to commit, changes are left in working tree");
+    }
+    let commit = crate::sign::commit(repo, &sig, &sig, msg, &tree, &[&parent_commit], gpg_sign)?;
+    Journal::record_commit(repo, commit)?;
+    crate::output::event(
+        "commit",
+        json!({ "oid": commit.to_string(), "message": msg.trim_end() }),
+    );
+    hooks::run_post_hook(repo, "post-commit", &[]);
     Ok(())
 }
 
 #[instrument(skip_all)]
-fn push_committed_changes(ui: &mut UI, repo: &Repository, no_verify: bool) -> Result<()> {
+fn push_committed_changes(
+    ui: &mut UI,
+    repo: &mut Repository,
+    pulling: &mut crate::pull::Pulling,
+    config: &Config,
+    run: &Run,
+) -> Result<()> {
+    let push_retries = config.push_retries;
     // Push the current branch using libgit2
-    let head = repo.head()?;
-    if let Some(branch) = head.shorthand() {
+    let branch = repo.head()?.shorthand().map(str::to_string);
+    if let Some(ref branch) = branch {
         ui.configure_pushing_progress(&Span::current(), branch);
-        if let Err(e) = push(repo, branch, no_verify) {
-            error!("Failed to push branch '{}': {}", branch, e);
-            SupState::Idle.save()?;
-            return Err(e);
+        let mut attempt = 0;
+        loop {
+            let e = match push(repo, branch, pulling.remote.as_deref(), config.no_verify) {
+                Ok(pushed) => {
+                    crate::output::event("push", json!(pushed));
+                    Journal::record_push(repo, pushed)?;
+                    break;
+                }
+                Err(e) => e,
+            };
+            let non_fast_forward = e
+                .downcast_ref::<PushRejectedError>()
+                .is_some_and(|rejected| rejected.non_fast_forward);
+            if !non_fast_forward || attempt >= push_retries {
+                error!("Failed to push branch '{}': {}", branch, e);
+                SupState::Idle.save(repo)?;
+                return Err(e);
+            }
+            if pulling.strategy == PullStrategy::FastForwardOnly {
+                // commit made by sup always diverges from moved upstream, so pull cannot succeed
+                error!("Failed to push branch '{}': {}", branch, e);
+                SupState::Idle.save(repo)?;
+                let message = format!(
+                    "{e}. Remote has new commits, which cannot be pulled with fast-forward only, \
+                    so commit was not pushed: integrate upstream changes with `git pull --rebase` \
+                    or `git pull --no-ff` and push it, and use `--rebase` or merge instead of \
+                    fast-forward only next time"
+                );
+                return Err(e.context(message));
+            }
+            attempt += 1;
+            crate::output::event(
+                "push_rejected",
+                json!({ "error": e.to_string(), "attempt": attempt, "max_attempts": push_retries }),
+            );
+            warn!(
+                "Push was rejected, integrating upstream changes before retry {}/{}",
+                attempt, push_retries
+            );
+            ui.configure_retrying_push_progress(&Span::current(), branch, attempt, push_retries);
+            let integrated = integrate_upstream(repo, pulling);
+            if let Err(e) = integrated {
+                error!("Failed to integrate upstream changes: {}", e);
+                run.not_pushed(is_rebase_in_progress(repo)).save(repo)?;
+                let message = format!(
+                    "Push was rejected and upstream changes could not be integrated: {e}. \
+                    Commit was not pushed: run `sup continue` to push it \
+                    after resolving the situation, or `sup abort` to roll back"
+                );
+                if check_conflicts(repo)? {
+                    return Err(conflict_error(repo, "push", message)?);
+                }
+                anyhow::bail!(message);
+            }
+            // pulled changes could break what was checked before
+            if let Some(check) = run.check {
+                if let Err(e) = check_committed(repo, check) {
+                    error!("Check failed after integrating upstream changes: {}", e);
+                    run.not_pushed(false).save(repo)?;
+                    return Err(e);
+                }
+            }
         }
     }
     Ok(())
 }
 
+/// Bring back changes which were not committed, if they were put aside when
+/// integrating upstream changes failed
+fn restore_uncommitted_changes(repo: &mut Repository) -> Result<()> {
+    let Some(stash_index) = find_uncommitted_stash(repo)? else {
+        return Ok(());
+    };
+    debug!("Restoring uncommitted changes from stash {}", stash_index);
+    unstash(repo, stash_index, true)
+}
+
+/// Index of stash with changes which were not committed, when it is on top of stash list
+fn find_uncommitted_stash(repo: &mut Repository) -> Result<Option<usize>> {
+    let mut found = None;
+    repo.stash_foreach(|stash_index, stash_msg, _| {
+        if stash_msg.ends_with(UNCOMMITTED_STASH_MESSAGE) {
+            found = Some(stash_index);
+        }
+        false
+    })?;
+    Ok(found)
+}
+
+/// Put changes which were not committed aside, returning id of the stash if there were any
+fn stash_uncommitted(repo: &mut Repository) -> Result<Option<String>> {
+    let sig = repo.signature()?;
+    match repo.stash_save(
+        &sig,
+        UNCOMMITTED_STASH_MESSAGE,
+        Some(StashFlags::INCLUDE_UNTRACKED),
+    ) {
+        Ok(oid) => Ok(Some(oid.to_string())),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Pull upstream changes before push is retried, keeping changes
+/// which were not committed aside in a stash meanwhile
+fn integrate_upstream(repo: &mut Repository, pulling: &mut crate::pull::Pulling) -> Result<()> {
+    let leftovers = stash_uncommitted(repo)?;
+    if let Err(e) = pulling.pull_run(repo, &crate::pull::Args::default()) {
+        if let Some(leftovers) = leftovers {
+            anyhow::bail!("{e}. Uncommitted changes are kept in stash {leftovers}");
+        }
+        return Err(e);
+    }
+    // working tree has to match the pulled result, as check runs against it
+    checking_out_with_force(repo)?;
+    if let Some(ref leftovers) = leftovers {
+        debug!("Restoring uncommitted changes from stash {}", leftovers);
+        let stash_index = find_sup_stash(repo, Some(leftovers))?;
+        unstash(repo, stash_index, true)
+            .with_context(|| format!("Uncommitted changes are kept in stash {leftovers}"))?;
+    }
+    Ok(())
+}
+
 fn stage_and_commit_with_hooks(
-    repo: &Repository,
-    message: &Option<String>,
+    repo: &mut Repository,
+    pulling: &mut crate::pull::Pulling,
     ui: &mut UI,
-    no_verify: bool,
+    config: &Config,
+    run: &Run,
 ) -> Result<(), anyhow::Error> {
-    if let Some(ref msg) = message {
-        commit_stashed_changes(ui, repo, msg, no_verify)?;
-        // Push the current branch using libgit2
-        push_committed_changes(ui, repo, no_verify)?;
+    if let Some(ref msg) = run.message {
+        commit_stashed_changes(
+            ui,
+            repo,
+            msg,
+            run.paths,
+            config.no_verify,
+            run.staged,
+            config.gpg_sign,
+        )?;
+        check_and_push(repo, pulling, ui, config, run)?;
     }
     Ok(())
 }
 
-fn push(repo: &Repository, branch: &str, no_verify: bool) -> anyhow::Result<()> {
-    // Run pre-push hook if present
-    if !no_verify {
-        // --no-verify skips pre-push hook
-        hooks::run_hook(repo, "pre-push", &["origin"])?;
+/// Run check against committed changes, if there is one, and push them when it passes
+fn check_and_push(
+    repo: &mut Repository,
+    pulling: &mut crate::pull::Pulling,
+    ui: &mut UI,
+    config: &Config,
+    run: &Run,
+) -> Result<()> {
+    if let Some(check) = run.check {
+        if let Err(e) = check_committed(repo, check) {
+            error!("Check failed: {}", e);
+            run.not_pushed(false).save(repo)?;
+            return Err(e);
+        }
+    }
+    // Push the current branch using libgit2
+    push_committed_changes(ui, repo, pulling, config, run)
+}
+
+/// Run check against committed result only, keeping changes which were not
+/// committed, like those left out by `--staged` or paths, aside in a stash meanwhile
+fn check_committed(repo: &mut Repository, check: &str) -> Result<()> {
+    let leftovers = stash_uncommitted(repo)?;
+    let result = crate::check::run(repo, check);
+    if let Some(ref leftovers) = leftovers {
+        debug!("Restoring uncommitted changes from stash {}", leftovers);
+        let stash_index = find_sup_stash(repo, Some(leftovers))?;
+        unstash(repo, stash_index, true)
+            .with_context(|| format!("Uncommitted changes are kept in stash {leftovers}"))?;
+    }
+    result
+}
+
+/// Returned when remote did not accept pushed reference
+#[derive(Debug)]
+pub(crate) struct PushRejectedError {
+    pub(crate) refname: String,
+    pub(crate) reason: String,
+    /// whether push was rejected because remote has commits that are not present locally
+    pub(crate) non_fast_forward: bool,
+}
+
+impl std::fmt::Display for PushRejectedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "push of {} was rejected: {}", self.refname, self.reason)
     }
-    let mut remote = repo.find_remote("origin")?;
-    let refspec = format!("refs/heads/{branch}:refs/heads/{branch}");
+}
+
+impl std::error::Error for PushRejectedError {}
+
+pub(crate) fn push(
+    repo: &Repository,
+    branch: &str,
+    remote: Option<&str>,
+    no_verify: bool,
+) -> Result<PushedRef> {
+    let target = crate::upstream::push_target(repo, branch, remote)?;
+    // --no-verify skips pre-push hook
+    let old_oid = push_refspec(
+        repo,
+        &target.remote,
+        &target.refspec(),
+        &target.dst,
+        None,
+        !no_verify,
+    )?;
+    Ok(PushedRef {
+        remote: target.remote,
+        new_oid: repo.refname_to_id(&target.src)?.to_string(),
+        refname: target.dst,
+        old_oid: old_oid.map(|oid| oid.to_string()),
+    })
+}
+
+/// Push refspec to remote, returning what remote reference `dst` pointed to before.
+/// With `expected` given, push is refused unless remote reference still points to it.
+/// With `verify`, pre-push hook is run first, given what remote references point to.
+pub(crate) fn push_refspec(
+    repo: &Repository,
+    remote_name: &str,
+    refspec: &str,
+    dst: &str,
+    expected: Option<git2::Oid>,
+    verify: bool,
+) -> Result<Option<git2::Oid>> {
+    let mut remote = repo.find_remote(remote_name)?;
+    // listing remote references for hook input needs another connection
+    if verify && hooks::exists(repo, "pre-push")? {
+        let url = remote
+            .pushurl()
+            .or(remote.url())
+            .unwrap_or(remote_name)
+            .to_string();
+        let input = pre_push_input(repo, &mut remote, refspec)?;
+        hooks::run_hook_with_input(repo, "pre-push", &[remote_name, &url], &input)?;
+    }
+    let mut rejection = None;
+    let mut old_oid = None;
+    let mut lease_broken = false;
+    let pushed = {
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(|url, username_from_url, allowed_types| {
+            crate::credentials::callback(url, username_from_url, &allowed_types, repo)
+        });
+        // remote reports rejected references here, while push itself succeeds
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                rejection = Some((refname.to_string(), status.to_string()));
+            }
+            Ok(())
+        });
+        // tells what remote references pointed to right before they are updated
+        callbacks.push_negotiation(|updates| {
+            for update in updates.iter().filter(|u| u.dst_refname() == Some(dst)) {
+                let remote_oid = Some(update.src()).filter(|oid| !oid.is_zero());
+                if expected.is_some() && remote_oid != expected {
+                    lease_broken = true;
+                    return Err(git2::Error::from_str(&format!(
+                        "{dst} was updated on remote since it was pushed by sup"
+                    )));
+                }
+                old_oid = remote_oid;
+            }
+            Ok(())
+        });
+        let mut push_options = git2::PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+        remote.push(&[refspec], Some(&mut push_options))
+    };
+    match pushed {
+        Err(e) if lease_broken => {
+            return Err(PushRejectedError {
+                refname: dst.to_string(),
+                reason: e.message().to_string(),
+                non_fast_forward: false,
+            }
+            .into());
+        }
+        Err(e) if e.code() == ErrorCode::NotFastForward => {
+            debug!("libgit2 rejected non-fast-forward push: {}", e);
+            return Err(PushRejectedError {
+                refname: dst.to_string(),
+                reason: e.message().to_string(),
+                non_fast_forward: true,
+            }
+            .into());
+        }
+        Err(e) => {
+            error!("libgit2 push failed: {}", e);
+            anyhow::bail!("libgit2 push failed: {}", e);
+        }
+        Ok(()) => {}
+    }
+    if let Some((refname, reason)) = rejection {
+        debug!("Remote rejected {}: {}", refname, reason);
+        let non_fast_forward = ["non-fast-forward", "fetch first", "stale info"]
+            .iter()
+            .any(|known| reason.contains(known));
+        return Err(PushRejectedError {
+            refname,
+            reason,
+            non_fast_forward,
+        }
+        .into());
+    }
+
+    Ok(old_oid)
+}
+
+/// Line `<local ref> <local oid> <remote ref> <remote oid>` git feeds to pre-push hook,
+/// where deleted reference is `(delete)` with zero oid, as is remote oid of a new reference
+fn pre_push_input(repo: &Repository, remote: &mut git2::Remote, refspec: &str) -> Result<Vec<u8>> {
+    let (src, dst) = refspec
+        .trim_start_matches('+')
+        .split_once(':')
+        .with_context(|| format!("Invalid refspec {refspec}"))?;
+    let (local_ref, local_oid) = if src.is_empty() {
+        ("(delete)", git2::Oid::zero())
+    } else {
+        (src, repo.refname_to_id(src)?)
+    };
     let mut callbacks = git2::RemoteCallbacks::new();
     callbacks.credentials(|url, username_from_url, allowed_types| {
         crate::credentials::callback(url, username_from_url, &allowed_types, repo)
     });
-    let mut push_options = git2::PushOptions::new();
-    push_options.remote_callbacks(callbacks);
-    remote
-        .push(&[&refspec], Some(&mut push_options))
-        .map_err(|e| {
-            error!("libgit2 push failed: {}", e);
-            anyhow::anyhow!("libgit2 push failed: {}", e)
-        })?;
-
-    Ok(())
+    let connection = remote.connect_auth(git2::Direction::Push, Some(callbacks), None)?;
+    let remote_oid = connection
+        .list()?
+        .iter()
+        .find(|head| head.name() == dst)
+        .map_or(git2::Oid::zero(), |head| head.oid());
+    Ok(format!("{local_ref} {local_oid} {dst} {remote_oid}\n").into_bytes())
 }
@@ -1,6 +1,11 @@
+use crate::output;
 use console::Emoji;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
 use tracing::Span;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
+use tracing_subscriber::layer::Context;
 
 static FLOPPY_DISK: Emoji<'_, '_> = Emoji("🗃️  ", "");
 static DOWN_ARROW: Emoji<'_, '_> = Emoji("🔽  ", "");
@@ -8,6 +13,57 @@ static ROCKET: Emoji<'_, '_> = Emoji("🚀 ", "");
 static CHECKMARK: Emoji<'_, '_> = Emoji("✅  ", "");
 static BOX: Emoji<'_, '_> = Emoji("📦  ", "");
 static RELOAD: Emoji<'_, '_> = Emoji("🔄  ", "");
+static WARNING: Emoji<'_, '_> = Emoji("⚠️  ", "");
+
+/// Finish messages of steps logged line by line, by id of their span
+static FINISH_MESSAGES: Mutex<BTreeMap<u64, String>> = Mutex::new(BTreeMap::new());
+
+/// Show step in the progress bar of its span, print it on its own line
+/// when there are no progress bars, or emit it as event in JSON output
+pub(crate) fn step(span: &Span, step: &str, message: &str, finish_message: &str) {
+    output::event("step", json!({ "step": step, "message": message }));
+    output::plain_line(message);
+    span.pb_set_message(message);
+    set_finish_message(span, finish_message);
+}
+
+/// Set message shown when the step of the span is finished
+pub(crate) fn set_finish_message(span: &Span, finish_message: &str) {
+    if finish_message.is_empty() {
+        return;
+    }
+    span.pb_set_finish_message(finish_message);
+    if let (true, Some(id)) = (output::is_plain(), span.id()) {
+        if let Ok(mut messages) = FINISH_MESSAGES.lock() {
+            messages.insert(id.into_u64(), finish_message.to_string());
+        }
+    }
+}
+
+/// Prints finish messages of steps when their spans are closed,
+/// in place of progress bars when output is logged line by line
+pub(crate) struct PlainLayer;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for PlainLayer {
+    fn on_close(&self, id: tracing::span::Id, _ctx: Context<'_, S>) {
+        let message = FINISH_MESSAGES
+            .lock()
+            .ok()
+            .and_then(|mut messages| messages.remove(&id.into_u64()));
+        if let Some(message) = message {
+            output::plain_line(&message);
+        }
+    }
+}
+
+/// Print a line, or emit it as event in JSON output
+fn log(event: &str, prefix: impl std::fmt::Display, message: &str) {
+    if output::is_json() {
+        output::event(event, json!({ "message": message }));
+    } else if !output::is_quiet() {
+        println!("{prefix}{message}");
+    }
+}
 
 pub(crate) struct UI {}
 
@@ -17,58 +73,188 @@ impl UI {
     }
 
     pub(crate) fn log_completed(&self) {
-        println!("       {CHECKMARK}Operation completed");
+        // in JSON output, completion is reported by the final result event
+        if !output::is_json() && !output::is_quiet() {
+            println!("       {CHECKMARK}Operation completed");
+        }
     }
 
     pub(crate) fn configure_stashing_progress(&self, span: &Span) {
-        span.pb_set_message("Stashing local changes");
-        span.pb_set_finish_message(&format!("{FLOPPY_DISK}Stashed local changes"));
+        step(
+            span,
+            "stashing",
+            "Stashing local changes",
+            &format!("{FLOPPY_DISK}Stashed local changes"),
+        );
     }
 
     pub(crate) fn configure_applying_stash_progress(&self, span: &Span) {
-        span.pb_set_message("Applying stashed changes");
-        span.pb_set_finish_message(&format!("{BOX}Applied stashed changes"));
+        step(
+            span,
+            "applying_stash",
+            "Applying stashed changes",
+            &format!("{BOX}Applied stashed changes"),
+        );
     }
 
     pub(crate) fn configure_pulling_progress(&self, span: &Span) {
-        span.pb_set_message("Pulling remote changes");
-        span.pb_set_finish_message(&format!("{DOWN_ARROW}Pulled remote changes"));
+        step(
+            span,
+            "pulling",
+            "Pulling remote changes",
+            &format!("{DOWN_ARROW}Pulled remote changes"),
+        );
     }
 
     pub(crate) fn log_abort(&mut self) {
-        println!("{RELOAD}Aborting and rolling back operation");
+        log("aborting", RELOAD, "Aborting and rolling back operation");
     }
 
     pub(crate) fn configure_resetting_progress(&mut self, span: &Span, orig_head: &str) {
-        span.pb_set_message(&format!(
-            "Resetting branch to original commit before pull: {orig_head}"
-        ));
-        span.pb_set_finish_message(&format!(
-            "{FLOPPY_DISK}Reset branch to commit before pull: {orig_head}"
-        ));
+        step(
+            span,
+            "resetting",
+            &format!("Resetting branch to original commit before pull: {orig_head}"),
+            &format!("{FLOPPY_DISK}Reset branch to commit before pull: {orig_head}"),
+        );
     }
 
     pub(crate) fn configure_restoring_stashed_changes_for_abort_progress(&mut self, span: &Span) {
-        span.pb_set_message("Restoring stashed changes after abort");
-        span.pb_set_finish_message(&format!("{BOX}Restored stashed changes"));
+        step(
+            span,
+            "restoring_stash",
+            "Restoring stashed changes after abort",
+            &format!("{BOX}Restored stashed changes"),
+        );
+    }
+
+    pub(crate) fn log_undo(&mut self) {
+        log("undoing", RELOAD, "Undoing last sup run");
+    }
+
+    pub(crate) fn configure_restoring_stashed_changes_for_undo_progress(&mut self, span: &Span) {
+        step(
+            span,
+            "restoring_stash",
+            "Restoring stashed changes from before last sup run",
+            &format!("{BOX}Restored stashed changes"),
+        );
+    }
+
+    pub(crate) fn configure_resetting_remote_progress(
+        &mut self,
+        span: &Span,
+        remote: &str,
+        refname: &str,
+    ) {
+        step(
+            span,
+            "resetting_remote",
+            &format!("Resetting {refname} on '{remote}' to where it was before push"),
+            &format!("{ROCKET}Reset {refname} on '{remote}'"),
+        );
+    }
+
+    pub(crate) fn configure_reverting_progress(&mut self, span: &Span) {
+        step(
+            span,
+            "reverting",
+            "Reverting commits pushed by last sup run",
+            &format!("{ROCKET}Pushed reverts of commits"),
+        );
     }
 
     pub(crate) fn configure_committing_stashed_changes_progress_bar(&mut self, span: &Span) {
-        span.pb_set_message("Committing stashed changes");
-        span.pb_set_finish_message(&format!("{CHECKMARK}Committed stashed changes"));
+        step(
+            span,
+            "committing",
+            "Committing stashed changes",
+            &format!("{CHECKMARK}Committed stashed changes"),
+        );
     }
 
     pub(crate) fn configure_pushing_progress(&mut self, span: &Span, branch: &str) {
-        span.pb_set_message(&format!("Pushing branch '{branch}'"));
-        span.pb_set_finish_message(&format!("{ROCKET}Pushed branch '{branch}'"));
+        step(
+            span,
+            "pushing",
+            &format!("Pushing branch '{branch}'"),
+            &format!("{ROCKET}Pushed branch '{branch}'"),
+        );
+    }
+
+    pub(crate) fn configure_retrying_push_progress(
+        &mut self,
+        span: &Span,
+        branch: &str,
+        attempt: u32,
+        max_attempts: u32,
+    ) {
+        step(
+            span,
+            "retrying_push",
+            &format!(
+                "Push was rejected, pulling and retrying to push branch '{branch}' ({attempt}/{max_attempts})"
+            ),
+            "",
+        );
+    }
+
+    pub(crate) fn log_pull_summary(&self, summary: &crate::summary::PullSummary) {
+        if output::is_json() {
+            output::event("pull_summary", json!(summary));
+            return;
+        }
+        if output::is_quiet() {
+            return;
+        }
+        let commits = if summary.commits == 1 {
+            "1 commit".to_string()
+        } else {
+            format!("{} commits", summary.commits)
+        };
+        println!("       Pulled {commits} by {}:", summary.authors.join(", "));
+        for line in &summary.log {
+            println!("         {line}");
+        }
+        if summary.commits > summary.log.len() {
+            println!(
+                "         ... and {} more",
+                summary.commits - summary.log.len()
+            );
+        }
+        for line in summary.diffstat.lines() {
+            println!("       {line}");
+        }
+        if !summary.lockfiles.is_empty() {
+            println!(
+                "{WARNING}Lockfiles changed, dependencies may need to be reinstalled: {}",
+                summary.lockfiles.join(", ")
+            );
+        }
+        if !summary.migrations.is_empty() {
+            println!(
+                "{WARNING}Migrations changed, they may need to be run: {}",
+                summary.migrations.join(", ")
+            );
+        }
+        if !summary.watched.is_empty() {
+            println!(
+                "{WARNING}Watched paths changed (sup.watch): {}",
+                summary.watched.join(", ")
+            );
+        }
     }
 
     pub(crate) fn log_continuing_interrupted_operation(&self) {
-        println!("{RELOAD}Continuing interrupted operation");
+        log("continuing", RELOAD, "Continuing interrupted operation");
     }
 
     pub(crate) fn configure_finishing_merge_progress(&mut self, span: &Span) {
-        span.pb_set_message("Finishing merge in progress (creating merge commit)");
-        span.pb_set_finish_message(&format!("{FLOPPY_DISK}Finished merge commit"));
+        step(
+            span,
+            "finishing_merge",
+            "Finishing merge in progress (creating merge commit)",
+            &format!("{FLOPPY_DISK}Finished merge commit"),
+        );
     }
 }
@@ -0,0 +1,227 @@
+//! `sup undo`: roll back the last completed sup run using its journal.
+
+use crate::config::Config;
+use crate::journal::{Journal, PushedRef};
+use crate::sup::{self, SupState};
+use crate::ui::UI;
+use anyhow::{Context, Result};
+use git2::{Oid, Repository, StatusOptions};
+use tracing::{debug, instrument, Span};
+
+/// Temporary reference used to push original commit back to remote
+const UNDO_REF: &str = "refs/sup/undo";
+
+pub fn run_undo(revert: bool, force_with_lease: bool, no_verify: bool) -> Result<()> {
+    if revert && force_with_lease {
+        anyhow::bail!("--revert and --force-with-lease cannot be used together");
+    }
+    sup::init_tracing();
+    let mut repo = sup::open_repo()?;
+    let command_line = if no_verify {
+        vec![("noVerify", "true".to_string())]
+    } else {
+        Vec::new()
+    };
+    let config = Config::load(&repo, &command_line)?;
+    let no_verify = config.no_verify;
+    let _lock_guard = crate::lock::lock(&repo, false)?;
+
+    if SupState::load(&repo)? != SupState::Idle {
+        anyhow::bail!(
+            "sup operation was interrupted, roll it back with `sup abort` or finish it with `sup continue`"
+        );
+    }
+    let Some(journal) = Journal::load(&repo)? else {
+        anyhow::bail!("Nothing to undo, no sup run was recorded");
+    };
+    let Some(head_after) = journal.head_after.as_deref() else {
+        anyhow::bail!("Last sup run has not completed, nothing to undo");
+    };
+    let (branch, head) = {
+        let head = repo.head()?;
+        let branch = if head.is_branch() {
+            head.name().map(str::to_string)
+        } else {
+            None
+        };
+        (branch, head.target().map(|oid| oid.to_string()))
+    };
+    if branch != journal.branch || head.as_deref() != Some(head_after) {
+        anyhow::bail!(
+            "HEAD has moved since last sup run (expected {head_after}), refusing to undo"
+        );
+    }
+
+    let mut ui = UI::new();
+    ui.log_undo();
+    match &journal.pushed {
+        None if revert || force_with_lease => anyhow::bail!(
+            "Last sup run did not push anything, run `sup undo` without --revert or --force-with-lease"
+        ),
+        None => reset_and_restore(&mut ui, &mut repo, &journal)?,
+        Some(_) if revert => revert_commits(&mut ui, &repo, &journal, &config)?,
+        Some(pushed) if force_with_lease => {
+            if is_dirty(&repo)? && !journal.commits.is_empty() {
+                anyhow::bail!("You have uncommitted changes, commit or stash them before undo");
+            }
+            reset_remote(&mut ui, &repo, pushed, no_verify)?;
+            reset_and_restore(&mut ui, &mut repo, &journal)?;
+        }
+        Some(pushed) => anyhow::bail!(
+            "Last sup run pushed {} to {}/{}. Run `sup undo --revert` to push commits reverting it, \
+            or `sup undo --force-with-lease` to reset remote branch to where it was",
+            pushed.new_oid,
+            pushed.remote,
+            pushed.refname
+        ),
+    }
+    Journal::clear(&repo)?;
+    ui.log_completed();
+    Ok(())
+}
+
+fn is_dirty(repo: &Repository) -> Result<bool> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).include_ignored(false);
+    Ok(!repo.statuses(Some(&mut options))?.is_empty())
+}
+
+/// Move branch back to original head and bring back local changes
+fn reset_and_restore(ui: &mut UI, repo: &mut Repository, journal: &Journal) -> Result<()> {
+    if journal.commits.is_empty() {
+        // stashed changes were applied to working tree and are still there,
+        // so keep them, as long as they do not touch files changed by pull
+        reset_keeping_changes(ui, repo, &journal.original_head)
+    } else {
+        if is_dirty(repo)? {
+            anyhow::bail!("You have uncommitted changes, commit or stash them before undo");
+        }
+        sup::reset_repo(ui, repo, &journal.original_head)?;
+        match journal.stash_oid.as_deref() {
+            Some(stash_oid) => restore_stash(ui, repo, stash_oid),
+            None => Ok(()),
+        }
+    }
+}
+
+#[instrument(skip_all)]
+fn reset_keeping_changes(ui: &mut UI, repo: &Repository, original_head: &str) -> Result<()> {
+    ui.configure_resetting_progress(&Span::current(), original_head);
+    let original = repo.find_object(
+        Oid::from_str(original_head)?,
+        Some(git2::ObjectType::Commit),
+    )?;
+    repo.checkout_tree(&original, Some(git2::build::CheckoutBuilder::new().safe()))
+        .context("Local changes conflict with changes made by last sup run, refusing to undo")?;
+    repo.reset(&original, git2::ResetType::Mixed, None)?;
+    Ok(())
+}
+
+/// Put stash created by sup back to the stash list and pop it
+#[instrument(skip_all)]
+fn restore_stash(ui: &mut UI, repo: &mut Repository, stash_oid: &str) -> Result<()> {
+    ui.configure_restoring_stashed_changes_for_undo_progress(&Span::current());
+    let oid = Oid::from_str(stash_oid)?;
+    let message = repo
+        .find_commit(oid)
+        .with_context(|| format!("sup stash {stash_oid} does not exist anymore"))?
+        .message()
+        .unwrap_or("sup stash")
+        .to_string();
+    repo.reference_ensure_log("refs/stash")?;
+    repo.reference("refs/stash", oid, true, &message)?;
+    let stash_index = sup::find_sup_stash(repo, Some(stash_oid))?;
+    sup::unstash(repo, stash_index, true).context("Failed to apply sup stash during undo")?;
+    debug!("sup stash {} restored", stash_oid);
+    Ok(())
+}
+
+/// Force remote reference back to what it was before push,
+/// unless someone else has updated it since
+#[instrument(skip_all)]
+fn reset_remote(ui: &mut UI, repo: &Repository, pushed: &PushedRef, no_verify: bool) -> Result<()> {
+    ui.configure_resetting_remote_progress(&Span::current(), &pushed.remote, &pushed.refname);
+    let verify = !no_verify;
+    let expected = Some(Oid::from_str(&pushed.new_oid)?);
+    match pushed.old_oid.as_deref() {
+        Some(old_oid) => {
+            repo.reference(UNDO_REF, Oid::from_str(old_oid)?, true, "sup: undo push")?;
+            let refspec = format!("+{UNDO_REF}:{}", pushed.refname);
+            let result = sup::push_refspec(
+                repo,
+                &pushed.remote,
+                &refspec,
+                &pushed.refname,
+                expected,
+                verify,
+            );
+            repo.find_reference(UNDO_REF)?.delete()?;
+            result?;
+        }
+        None => {
+            // branch was created by push, so delete it
+            let refspec = format!(":{}", pushed.refname);
+            sup::push_refspec(
+                repo,
+                &pushed.remote,
+                &refspec,
+                &pushed.refname,
+                expected,
+                verify,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Commit reverts of commits created by sup, push them,
+/// and bring reverted changes back to working tree
+#[instrument(skip_all)]
+fn revert_commits(
+    ui: &mut UI,
+    repo: &Repository,
+    journal: &Journal,
+    config: &Config,
+) -> Result<()> {
+    ui.configure_reverting_progress(&Span::current());
+    if is_dirty(repo)? {
+        anyhow::bail!("You have uncommitted changes, commit or stash them before undo");
+    }
+    let sig = repo.signature()?;
+    let mut reverted = Vec::new();
+    for commit in journal.commits.iter().rev() {
+        let commit = repo.find_commit(Oid::from_str(commit)?)?;
+        let head = repo.head()?.peel_to_commit()?;
+        let mut index = repo.revert_commit(&commit, &head, 0, None)?;
+        if index.has_conflicts() {
+            anyhow::bail!(
+                "Reverting {} conflicts with later changes, revert it manually",
+                commit.id()
+            );
+        }
+        let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        let message = format!(
+            "Revert \"{}\"\n\nThis reverts commit {}.\n",
+            commit.summary().unwrap_or_default(),
+            commit.id()
+        );
+        crate::sign::commit(repo, &sig, &sig, &message, &tree, &[&head], config.gpg_sign)?;
+        reverted.push(commit);
+    }
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+
+    let branch = repo.head()?.shorthand().map(str::to_string);
+    let Some(branch) = branch else {
+        anyhow::bail!("Cannot push reverts from detached HEAD");
+    };
+    // reverts go where the reverted commits were pushed
+    let remote = journal.pushed.as_ref().map(|pushed| pushed.remote.as_str());
+    sup::push(repo, &branch, remote, config.no_verify)?;
+
+    for commit in reverted.iter().rev() {
+        let parent_tree = commit.parent(0)?.tree()?;
+        let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&commit.tree()?), None)?;
+        repo.apply(&diff, git2::ApplyLocation::WorkDir, None)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! Resolution of remotes and branches that current branch is tracking,
+//! following the same git config that `git pull` and `git push` use.
+
+use anyhow::Result;
+use git2::Repository;
+
+/// Where current branch should be pulled from
+#[derive(Debug)]
+pub(crate) struct PullSource {
+    pub(crate) remote: String,
+    /// full name of reference on remote, like refs/heads/main
+    pub(crate) merge: String,
+}
+
+impl PullSource {
+    /// Name of the remote branch without refs/heads/ prefix
+    pub(crate) fn branch(&self) -> &str {
+        self.merge
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&self.merge)
+    }
+
+    /// Remote-tracking reference which fetched branch is stored in
+    pub(crate) fn tracking_ref(&self) -> String {
+        format!("refs/remotes/{}/{}", self.remote, self.branch())
+    }
+}
+
+/// Where current branch should be pushed to
+#[derive(Debug)]
+pub(crate) struct PushTarget {
+    pub(crate) remote: String,
+    /// full name of local reference, like refs/heads/feature
+    pub(crate) src: String,
+    /// full name of reference on remote, like refs/heads/main
+    pub(crate) dst: String,
+}
+
+impl PushTarget {
+    pub(crate) fn refspec(&self) -> String {
+        format!("{}:{}", self.src, self.dst)
+    }
+}
+
+/// Remote that local branch is tracking, as configured in `branch.<name>.remote`
+pub(crate) fn upstream_remote(repo: &Repository, branch: &str) -> Result<Option<String>> {
+    let config = repo.config()?;
+    Ok(config.get_string(&format!("branch.{branch}.remote")).ok())
+}
+
+/// Resolve what to pull into current HEAD: remote and branch from `branch.<name>.remote`
+/// and `branch.<name>.merge`, falling back to the default branch of the remote
+/// (refs/remotes/<remote>/HEAD), for example when HEAD is detached.
+/// Explicitly given remote and branch take precedence.
+pub(crate) fn pull_source(
+    repo: &Repository,
+    remote: Option<&str>,
+    branch: Option<&str>,
+) -> Result<PullSource> {
+    let config = repo.config()?;
+    let head = repo.find_reference("HEAD")?;
+    let local_branch = head
+        .symbolic_target()
+        .and_then(|target| target.strip_prefix("refs/heads/"));
+
+    let configured_remote = match local_branch {
+        Some(local_branch) => upstream_remote(repo, local_branch)?,
+        None => None,
+    };
+    let remote = match remote.map(str::to_string).or(configured_remote.clone()) {
+        Some(remote) => remote,
+        None => {
+            let remotes = repo.remotes()?;
+            let names: Vec<&str> = remotes.iter().flatten().collect();
+            match names.as_slice() {
+                [single] => single.to_string(),
+                _ if names.contains(&"origin") => "origin".to_string(),
+                _ => anyhow::bail!(
+                    "Cannot determine remote to pull from: current branch has no upstream configured \
+                    and there is no \"origin\" remote. Set it with `git branch --set-upstream-to`"
+                ),
+            }
+        }
+    };
+
+    if let Some(branch) = branch {
+        return Ok(PullSource {
+            remote,
+            merge: format!("refs/heads/{branch}"),
+        });
+    }
+
+    // branch.<name>.merge only makes sense together with the remote it was configured for
+    if let Some(local_branch) = local_branch {
+        if configured_remote.as_deref() == Some(remote.as_str()) {
+            if let Ok(merge) = config.get_string(&format!("branch.{local_branch}.merge")) {
+                return Ok(PullSource { remote, merge });
+            }
+        }
+    }
+
+    let remote_head = format!("refs/remotes/{remote}/HEAD");
+    let default_branch = repo
+        .find_reference(&remote_head)
+        .ok()
+        .and_then(|reference| reference.symbolic_target().map(str::to_string))
+        .and_then(|target| {
+            target
+                .strip_prefix(&format!("refs/remotes/{remote}/"))
+                .map(|branch| format!("refs/heads/{branch}"))
+        });
+    match default_branch {
+        Some(merge) => {
+            tracing::debug!("Using default branch of remote {}: {}", remote, merge);
+            Ok(PullSource { remote, merge })
+        }
+        None => {
+            let current = match local_branch {
+                Some(local_branch) => format!("branch '{local_branch}'"),
+                None => "detached HEAD".to_string(),
+            };
+            anyhow::bail!(
+                "No upstream branch to pull from: {current} has no upstream configured \
+                and default branch of remote '{remote}' is not known. \
+                Set upstream with `git branch --set-upstream-to={remote}/<branch>` \
+                or default branch with `git remote set-head {remote} --auto`"
+            )
+        }
+    }
+}
+
+/// Resolve push destination for local branch, honoring `branch.<name>.pushRemote`,
+/// `remote.pushDefault`, `branch.<name>.remote`, `branch.<name>.merge` and `push.default`.
+/// Explicitly given remote takes precedence.
+pub(crate) fn push_target(
+    repo: &Repository,
+    branch: &str,
+    remote: Option<&str>,
+) -> Result<PushTarget> {
+    let config = repo.config()?;
+    let get = |key: &str| config.get_string(key).ok();
+
+    let upstream_remote = upstream_remote(repo, branch)?;
+    let remote = remote
+        .map(str::to_string)
+        .or_else(|| get(&format!("branch.{branch}.pushRemote")))
+        .or_else(|| get("remote.pushDefault"))
+        .or_else(|| upstream_remote.clone())
+        .unwrap_or_else(|| "origin".to_string());
+    let merge = get(&format!("branch.{branch}.merge"));
+    let pushing_to_upstream = upstream_remote.as_deref() == Some(remote.as_str());
+
+    let src = format!("refs/heads/{branch}");
+    let push_default = get("push.default").unwrap_or_else(|| "simple".to_string());
+    let dst = match push_default.as_str() {
+        "nothing" => {
+            anyhow::bail!("push.default is set to \"nothing\", refusing to push branch '{branch}'")
+        }
+        "current" | "matching" => src.clone(),
+        "upstream" | "tracking" => match merge {
+            Some(merge) if pushing_to_upstream => merge,
+            _ => anyhow::bail!(
+                "Branch '{branch}' has no upstream branch on remote '{remote}' to push to \
+                (push.default is \"{push_default}\"), set it with `git branch --set-upstream-to`"
+            ),
+        },
+        // "simple": when pushing back to the remote branch is tracking,
+        // update tracked branch even if it is named differently
+        _ => match merge {
+            Some(merge) if pushing_to_upstream => merge,
+            _ => src.clone(),
+        },
+    };
+    tracing::debug!(
+        "Resolved push target {}:{} -> {} (push.default={})",
+        remote,
+        src,
+        dst,
+        push_default
+    );
+    Ok(PushTarget { remote, src, dst })
+}
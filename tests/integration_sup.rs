@@ -20,7 +20,8 @@ fn file_url(path: &Path) -> String {
 
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 fn git_log(path: &Path) -> String {
@@ -28,6 +29,7 @@ fn git_log(path: &Path) -> String {
         .arg("log")
         .arg("--graph")
         .arg("--format=%f")
+        .arg("--exclude=refs/sup/*")
         .arg("--all")
         .current_dir(path)
         .output()
@@ -384,6 +386,34 @@ fn test_abort_on_conflicting_commit_and_uncommitted_change() {
     ");
 }
 
+#[test]
+fn test_abort_keeps_partially_staged_change_staged() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    fs::write(other.join("file.txt"), "updated\n").unwrap();
+    run_git(&other, &["commit", "-am", "update"]);
+    run_git(&other, &["push", "origin", "master"]);
+    fs::write(repo2.join("file.txt"), "localchange\n").unwrap();
+    run_git(&repo2, &["commit", "-am", "local change"]);
+
+    // part of the change is staged, the rest is not
+    fs::write(repo2.join("notes.txt"), "staged\n").unwrap();
+    run_git(&repo2, &["add", "notes.txt"]);
+    fs::write(repo2.join("notes.txt"), "staged\nunstaged\n").unwrap();
+    let staged = git_stdout(&repo2, &["diff", "--cached"]);
+    let unstaged = git_stdout(&repo2, &["diff"]);
+
+    run_sup(&repo2, &[], true);
+    run_sup(&repo2, &["abort"], false);
+    assert_eq!(git_stdout(&repo2, &["diff", "--cached"]), staged);
+    assert_eq!(git_stdout(&repo2, &["diff"]), unstaged);
+    assert_eq!(stash_list(&repo2), "");
+}
+
 #[test]
 fn test_abort_on_conflicting_uncommited_change() {
     let temp = tempfile::tempdir().unwrap();
@@ -1029,3 +1059,2216 @@ fn test_commit_and_push_after_automatic_normal_merge() {
     ");
     });
 }
+
+#[test]
+fn test_commit_and_push_after_automatic_rebase() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1_bare");
+    let repo2 = temp.path().join("repo2");
+    // Create bare repo1
+    run_git(temp.path(), &["init", "--bare", "repo1_bare"]);
+
+    // Clone repo1 to repo2 (creates working directory)
+    let repo1_url = file_url(&repo1);
+    run_git(temp.path(), &["clone", &repo1_url, "repo2"]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // Initial commit in repo2, then push to bare repo1
+    fs::write(
+        repo2.join("file.txt"),
+        "line0\ninitial-line1\nline2\nline3\nline4\nline5\n",
+    )
+    .unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "initial"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+
+    // Simulate remote change: clone repo1 to temp remote_work, commit, push
+    let remote_work = temp.path().join("remote_work");
+    run_git(temp.path(), &["clone", &repo1_url, "remote_work"]);
+    run_git(&remote_work, &["config", "user.email", "test@example.com"]);
+    run_git(&remote_work, &["config", "user.name", "Test"]);
+    fs::write(
+        remote_work.join("file.txt"),
+        "line0\nupdated-line1\nline2\nline3\nline4\nline5\n",
+    )
+    .unwrap();
+    run_git(&remote_work, &["add", "."]);
+    run_git(&remote_work, &["commit", "-m", "update"]);
+    run_git(&remote_work, &["push", "origin", "master"]);
+
+    // In repo2: make committed non-conflicting change that diverges from remote
+    fs::write(
+        repo2.join("file.txt"),
+        "line0\ninitial-line1\nline2\nline3\nline4-changed\nline5\n",
+    )
+    .unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local change"]);
+
+    // make uncommitted non-conflicting change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2 with rebase (should succeed and push linear history)
+    run_sup(&repo2, &["-m", "commit message", "--rebase"], false);
+
+    // check that file.txt has both changes
+    let content = file_content(&repo2.join("file.txt"));
+    assert_eq!(
+        content,
+        "line0\nupdated-line1\nline2\nline3\nline4-changed\nline5\n"
+    );
+    let content = file_content(&repo2.join("file2.txt"));
+    assert_eq!(content, "localnewfile\n");
+
+    // verify that the commits were pushed to the remote
+    let verify_repo = temp.path().join("verify");
+    run_git(temp.path(), &["clone", &repo1_url, "verify"]);
+    let content = fs::read_to_string(verify_repo.join("file2.txt")).unwrap();
+    assert_eq!(content, "localnewfile\n");
+
+    insta::assert_snapshot!(
+    git_log(&repo2),
+    @r"
+    * commit-message
+    * local-change
+    * update
+    * initial
+    ");
+}
+
+#[test]
+fn test_continue_rebase_from_git_config_after_conflict_resolution() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1_bare");
+    let repo2 = temp.path().join("repo2");
+    // Create bare repo1
+    run_git(temp.path(), &["init", "--bare", "repo1_bare"]);
+
+    // Clone repo1 to repo2 (creates working directory)
+    let repo1_url = file_url(&repo1);
+    run_git(temp.path(), &["clone", &repo1_url, "repo2"]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+    run_git(&repo2, &["config", "pull.rebase", "true"]);
+
+    // Initial commit in repo2, then push to bare repo1
+    fs::write(repo2.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "initial"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+
+    // Simulate remote change: clone repo1 to temp remote_work, commit, push
+    let remote_work = temp.path().join("remote_work");
+    run_git(temp.path(), &["clone", &repo1_url, "remote_work"]);
+    run_git(&remote_work, &["config", "user.email", "test@example.com"]);
+    run_git(&remote_work, &["config", "user.name", "Test"]);
+    fs::write(remote_work.join("file.txt"), "updated\n").unwrap();
+    run_git(&remote_work, &["add", "."]);
+    run_git(&remote_work, &["commit", "-m", "update"]);
+    run_git(&remote_work, &["push", "origin", "master"]);
+
+    // In repo2: make committed conflicting change
+    fs::write(repo2.join("file.txt"), "localchange\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local change"]);
+
+    // make uncommitted non-conflicting change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2 (should stop on conflict while rebasing)
+    run_sup(&repo2, &["-m", "commit message"], true);
+
+    let content = file_content(&repo2.join("file.txt"));
+    assert!(
+        content.contains("localchange") && content.contains("updated"),
+        "expected conflict markers, got: {content}"
+    );
+
+    // resolve conflict manually and stage it, leaving commit to sup
+    fs::write(repo2.join("file.txt"), "resolved\n").unwrap();
+    run_git(&repo2, &["add", "file.txt"]);
+
+    // run sup with --continue (should finish rebase, apply stash, commit and push)
+    run_sup(&repo2, &["--continue"], false);
+
+    let content = file_content(&repo2.join("file.txt"));
+    assert_eq!(content, "resolved\n");
+    let content = file_content(&repo2.join("file2.txt"));
+    assert_eq!(content, "localnewfile\n");
+
+    // verify that the commits were pushed to the remote
+    let verify_repo = temp.path().join("verify");
+    run_git(temp.path(), &["clone", &repo1_url, "verify"]);
+    let content = fs::read_to_string(verify_repo.join("file.txt")).unwrap();
+    assert_eq!(content, "resolved\n");
+
+    insta::assert_snapshot!(
+    git_log(&repo2),
+    @r"
+    * commit-message
+    * local-change
+    * update
+    * initial
+    ");
+}
+
+#[test]
+fn test_abort_rebase_on_conflicting_commit() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1");
+    let repo2 = temp.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+
+    // init repo1
+    run_git(&repo1, &["init"]);
+    run_git(&repo1, &["config", "user.email", "test@example.com"]);
+    run_git(&repo1, &["config", "user.name", "Test"]);
+    fs::write(repo1.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "initial"]);
+
+    // clone repo1 to repo2
+    let repo1_url = file_url(&repo1);
+    run_git(&repo2, &["clone", &repo1_url, "."]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // change repo1
+    fs::write(repo1.join("file.txt"), "updated\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "update"]);
+
+    // make commited conflicting change in repo2
+    fs::write(repo2.join("file.txt"), "localchange\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local change"]);
+
+    // make uncommitted non conflicting change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2 with rebase, should stop on conflict
+    run_sup(&repo2, &["--rebase"], true);
+
+    // run sup with abort flag
+    run_sup(&repo2, &["--abort"], false);
+
+    // check repo2 is back on its branch with both local changes
+    let content = file_content(&repo2.join("file.txt"));
+    assert_eq!(content, "localchange\n");
+    let content = file_content(&repo2.join("file2.txt"));
+    assert_eq!(content, "localnewfile\n");
+    assert!(!repo2.join(".git/rebase-merge").exists());
+
+    insta::assert_snapshot!(
+    git_log(&repo2),
+    @r"
+    * local-change
+    | * update
+    |/  
+    * initial
+    ");
+}
+
+#[test]
+fn test_ff_only_refuses_diverged_branch_and_restores_changes() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1");
+    let repo2 = temp.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+
+    // init repo1
+    run_git(&repo1, &["init"]);
+    run_git(&repo1, &["config", "user.email", "test@example.com"]);
+    run_git(&repo1, &["config", "user.name", "Test"]);
+    fs::write(repo1.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "initial"]);
+
+    // clone repo1 to repo2
+    let repo1_url = file_url(&repo1);
+    run_git(&repo2, &["clone", &repo1_url, "."]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+    run_git(&repo2, &["config", "pull.ff", "only"]);
+
+    // change repo1
+    fs::write(repo1.join("file.txt"), "updated\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "update"]);
+
+    // make committed non conflicting change in repo2, so branches diverge
+    fs::write(repo2.join("another_file.txt"), "local_change\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local change"]);
+
+    // make uncommitted change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2, should refuse to create merge commit
+    run_sup(&repo2, &[], true);
+
+    // local changes are given back and nothing is left to continue or abort
+    let content = file_content(&repo2.join("file2.txt"));
+    assert_eq!(content, "localnewfile\n");
+    let content = file_content(&repo2.join("file.txt"));
+    assert_eq!(content, "initial\n");
+    assert!(!repo2.join(".git/sup_state").exists());
+
+    insta::assert_snapshot!(
+    git_log(&repo2),
+    @r"
+    * local-change
+    | * update
+    |/  
+    * initial
+    ");
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_push_is_retried_after_rejection_by_concurrent_remote_change() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1_bare");
+    let repo2 = temp.path().join("repo2");
+    // Create bare repo1
+    run_git(temp.path(), &["init", "--bare", "repo1_bare"]);
+
+    // Clone repo1 to repo2 (creates working directory)
+    let repo1_url = file_url(&repo1);
+    run_git(temp.path(), &["clone", &repo1_url, "repo2"]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // Initial commit in repo2, then push to bare repo1
+    fs::write(repo2.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "initial"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+
+    // Teammate's clone which pushes right before our push
+    let remote_work = temp.path().join("remote_work");
+    run_git(temp.path(), &["clone", &repo1_url, "remote_work"]);
+    run_git(&remote_work, &["config", "user.email", "test@example.com"]);
+    run_git(&remote_work, &["config", "user.name", "Test"]);
+
+    // make uncommitted change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // pre-push hook that wins the race once, by pushing teammate's commit first
+    let hooks_dir = repo2.join(".git/hooks");
+    fs::create_dir_all(&hooks_dir).unwrap();
+    let hook_path = hooks_dir.join("pre-push");
+    let marker = temp.path().join("raced");
+    fs::write(
+        &hook_path,
+        format!(
+            "#!/bin/sh\n\
+            unset GIT_DIR GIT_INDEX_FILE GIT_WORK_TREE\n\
+            if [ -e '{marker}' ]; then exit 0; fi\n\
+            touch '{marker}'\n\
+            cd '{remote_work}' || exit 1\n\
+            echo teammate > teammate.txt\n\
+            git add . && git commit -q -m 'teammate change' && git push -q origin master\n",
+            marker = marker.display(),
+            remote_work = remote_work.display(),
+        ),
+    )
+    .unwrap();
+    let mut perms = fs::metadata(&hook_path).unwrap().permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(&hook_path, perms).unwrap();
+
+    // run sup in repo2 with rebase, first push is rejected, second succeeds
+    run_sup(&repo2, &["-m", "commit message", "--rebase"], false);
+    assert!(marker.exists());
+
+    // verify that both changes are on the remote
+    let verify_repo = temp.path().join("verify");
+    run_git(temp.path(), &["clone", &repo1_url, "verify"]);
+    let content = fs::read_to_string(verify_repo.join("file2.txt")).unwrap();
+    assert_eq!(content, "localnewfile\n");
+    let content = fs::read_to_string(verify_repo.join("teammate.txt")).unwrap();
+    assert_eq!(content, "teammate\n");
+
+    insta::assert_snapshot!(
+    git_log(&verify_repo),
+    @r"
+    * commit-message
+    * teammate-change
+    * initial
+    ");
+}
+
+/// Install pre-push hook which pushes teammate's commit made by `change` in `remote_work`
+/// right before the first push, so that it is rejected
+#[cfg(not(windows))]
+fn racing_pre_push_hook(repo: &Path, remote_work: &Path, marker: &Path, change: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let hook_path = repo.join(".git/hooks/pre-push");
+    fs::create_dir_all(hook_path.parent().unwrap()).unwrap();
+    fs::write(
+        &hook_path,
+        format!(
+            "#!/bin/sh\n\
+            unset GIT_DIR GIT_INDEX_FILE GIT_WORK_TREE\n\
+            if [ -e '{marker}' ]; then exit 0; fi\n\
+            touch '{marker}'\n\
+            cd '{remote_work}' || exit 1\n\
+            {change}\n\
+            git add . && git commit -q -m 'teammate change' && git push -q origin master\n",
+            marker = marker.display(),
+            remote_work = remote_work.display(),
+        ),
+    )
+    .unwrap();
+    let mut perms = fs::metadata(&hook_path).unwrap().permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(&hook_path, perms).unwrap();
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_continue_after_push_retry_conflicts_with_concurrent_remote_change() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let remote_work = temp.path().join("remote_work");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "remote_work"]);
+    run_git(&remote_work, &["config", "user.email", "test@example.com"]);
+    run_git(&remote_work, &["config", "user.name", "Test"]);
+
+    // pre-push hook that wins the race once, changing the same line as our commit
+    let marker = temp.path().join("raced");
+    racing_pre_push_hook(&repo2, &remote_work, &marker, "echo teammate > file.txt");
+
+    // only file.txt is committed, while notes.txt stays uncommitted
+    fs::write(repo2.join("file.txt"), "local\n").unwrap();
+    fs::write(repo2.join("notes.txt"), "notes\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["-m", "local change", "--", "file.txt"])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(marker.exists());
+    let status = sup_output(&repo2, &["status"]);
+    assert!(status.contains("sup state: interrupted"), "{status}");
+    assert!(status.contains("changes are committed, not pushed yet"), "{status}");
+    assert!(status.contains("conflicted files:\n    file.txt"), "{status}");
+
+    // after conflict is resolved, continue finishes the merge and pushes it
+    fs::write(repo2.join("file.txt"), "local and teammate\n").unwrap();
+    run_git(&repo2, &["add", "file.txt"]);
+    run_sup(&repo2, &["continue"], false);
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&repo2, "HEAD"));
+    assert_eq!(rev_parse(&repo2, "HEAD^2"), rev_parse(&remote_work, "HEAD"));
+    assert_eq!(file_content(&repo2.join("notes.txt")), "notes\n");
+    assert_eq!(stash_list(&repo2), "");
+    assert!(!repo2.join(".git/sup_state").exists());
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_push_is_not_retried_with_ff_only_when_remote_has_moved() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    run_git(&repo2, &["config", "pull.ff", "only"]);
+    let remote_work = temp.path().join("remote_work");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "remote_work"]);
+    run_git(&remote_work, &["config", "user.email", "test@example.com"]);
+    run_git(&remote_work, &["config", "user.name", "Test"]);
+    let marker = temp.path().join("raced");
+    racing_pre_push_hook(&repo2, &remote_work, &marker, "echo teammate > teammate.txt");
+
+    fs::write(repo2.join("local.txt"), "local\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["-m", "local change"])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert_eq!(output.status.code(), Some(4));
+    assert!(marker.exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("cannot be pulled with fast-forward only"), "{stdout}");
+    assert!(stdout.contains("git pull --rebase"), "{stdout}");
+    assert!(!stdout.contains("retrying"), "{stdout}");
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&remote_work, "HEAD"));
+    assert!(sup_output(&repo2, &["status"]).contains("idle"));
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_check_runs_again_after_upstream_changes_are_integrated_for_push_retry() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let pushed = rev_parse(&repo1, "master");
+    let remote_work = temp.path().join("remote_work");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "remote_work"]);
+    run_git(&remote_work, &["config", "user.email", "test@example.com"]);
+    run_git(&remote_work, &["config", "user.name", "Test"]);
+    let marker = temp.path().join("raced");
+    racing_pre_push_hook(&repo2, &remote_work, &marker, "echo broken > broken.txt");
+
+    // check passes on the commit alone, but not with teammate's change pulled for retry
+    fs::write(repo2.join("local.txt"), "local\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["-m", "local change", "--check", "test ! -e broken.txt"])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(marker.exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Check test ! -e broken.txt passed"), "{stdout}");
+    assert!(stdout.contains("Check test ! -e broken.txt failed"), "{stdout}");
+    assert_ne!(rev_parse(&repo1, "master"), pushed);
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&remote_work, "HEAD"));
+    let status = sup_output(&repo2, &["status"]);
+    assert!(status.contains("changes are committed, not pushed yet"), "{status}");
+
+    // continue checks again, so once the breakage is fixed the merge is pushed
+    fs::remove_file(repo2.join("broken.txt")).unwrap();
+    run_git(&repo2, &["commit", "-aqm", "fix breakage"]);
+    run_sup(&repo2, &["continue"], false);
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&repo2, "HEAD"));
+    assert_eq!(file_content(&repo2.join("local.txt")), "local\n");
+    assert_eq!(stash_list(&repo2), "");
+}
+
+#[test]
+fn test_commit_and_push_to_remote_named_upstream() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1_bare");
+    let repo2 = temp.path().join("repo2");
+    // Create bare repo1
+    run_git(temp.path(), &["init", "--bare", "repo1_bare"]);
+
+    // Clone repo1 to repo2 with remote named "upstream" instead of "origin"
+    let repo1_url = file_url(&repo1);
+    run_git(temp.path(), &["clone", "-o", "upstream", &repo1_url, "repo2"]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // Initial commit in repo2, then push to bare repo1
+    fs::write(repo2.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "initial"]);
+    run_git(&repo2, &["push", "upstream", "master"]);
+
+    // make uncommitted change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2, should push to "upstream"
+    run_sup(&repo2, &["-m", "commit message"], false);
+
+    // verify that the commit was pushed to the remote
+    let verify_repo = temp.path().join("verify");
+    run_git(temp.path(), &["clone", &repo1_url, "verify"]);
+    let content = fs::read_to_string(verify_repo.join("file2.txt")).unwrap();
+    assert_eq!(content, "localnewfile\n");
+}
+
+#[test]
+fn test_commit_and_push_to_configured_push_remote() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1_bare");
+    let fork = temp.path().join("fork_bare");
+    let repo2 = temp.path().join("repo2");
+    run_git(temp.path(), &["init", "--bare", "repo1_bare"]);
+    run_git(temp.path(), &["init", "--bare", "fork_bare"]);
+
+    // Clone repo1 to repo2 and add fork as a second remote to push to
+    let repo1_url = file_url(&repo1);
+    let fork_url = file_url(&fork);
+    run_git(temp.path(), &["clone", &repo1_url, "repo2"]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+    run_git(&repo2, &["remote", "add", "fork", &fork_url]);
+
+    // Initial commit in repo2, then push to both remotes
+    fs::write(repo2.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "initial"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+    run_git(&repo2, &["push", "fork", "master"]);
+    run_git(&repo2, &["config", "remote.pushDefault", "fork"]);
+
+    // make uncommitted change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2, should pull from origin, but push to fork
+    run_sup(&repo2, &["-m", "commit message"], false);
+
+    let verify_fork = temp.path().join("verify_fork");
+    run_git(temp.path(), &["clone", &fork_url, "verify_fork"]);
+    let content = fs::read_to_string(verify_fork.join("file2.txt")).unwrap();
+    assert_eq!(content, "localnewfile\n");
+
+    let verify_origin = temp.path().join("verify_origin");
+    run_git(temp.path(), &["clone", &repo1_url, "verify_origin"]);
+    assert!(!verify_origin.join("file2.txt").exists());
+}
+
+#[test]
+fn test_pull_with_cli_uses_configured_remote() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    // second remote has a commit that origin does not have
+    let other = temp.path().join("other_bare");
+    run_git(temp.path(), &["clone", "--bare", &file_url(&repo1), "other_bare"]);
+    let other_url = file_url(&other);
+    let other_work = temp.path().join("other_work");
+    run_git(temp.path(), &["clone", &other_url, "other_work"]);
+    run_git(&other_work, &["config", "user.email", "test@example.com"]);
+    run_git(&other_work, &["config", "user.name", "Test"]);
+    fs::write(other_work.join("other.txt"), "from other\n").unwrap();
+    run_git(&other_work, &["add", "."]);
+    run_git(&other_work, &["commit", "-m", "other commit"]);
+    run_git(&other_work, &["push", "origin", "master"]);
+
+    run_git(&repo2, &["remote", "add", "other", &other_url]);
+    run_git(&repo2, &["fetch", "other"]);
+    run_git(&repo2, &["remote", "set-head", "other", "--auto"]);
+    run_git(&repo2, &["config", "sup.remote", "other"]);
+    run_git(&repo2, &["config", "sup.pullWithCli", "true"]);
+
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_sup(&repo2, &["-m", "commit message"], false);
+
+    assert_eq!(file_content(&repo2.join("other.txt")), "from other\n");
+    assert_eq!(
+        git_stdout(&other, &["log", "-1", "--format=%s", "master"]),
+        "commit message\n"
+    );
+}
+
+#[test]
+fn test_pull_and_push_branch_tracking_differently_named_upstream() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1_bare");
+    let repo2 = temp.path().join("repo2");
+    // Create bare repo1
+    run_git(temp.path(), &["init", "--bare", "repo1_bare"]);
+
+    // Clone repo1 to repo2 (creates working directory)
+    let repo1_url = file_url(&repo1);
+    run_git(temp.path(), &["clone", &repo1_url, "repo2"]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // Initial commit in repo2, then push to bare repo1
+    fs::write(repo2.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "initial"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+
+    // local branch "feature" tracks origin/master
+    run_git(&repo2, &["checkout", "-b", "feature", "origin/master"]);
+
+    // Simulate remote change: clone repo1 to temp remote_work, commit, push
+    let remote_work = temp.path().join("remote_work");
+    run_git(temp.path(), &["clone", &repo1_url, "remote_work"]);
+    run_git(&remote_work, &["config", "user.email", "test@example.com"]);
+    run_git(&remote_work, &["config", "user.name", "Test"]);
+    fs::write(remote_work.join("file.txt"), "updated\n").unwrap();
+    run_git(&remote_work, &["add", "."]);
+    run_git(&remote_work, &["commit", "-m", "update"]);
+    run_git(&remote_work, &["push", "origin", "master"]);
+
+    // make uncommitted change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2, should pull master into feature and push feature to master
+    run_sup(&repo2, &["-m", "commit message"], false);
+
+    let content = file_content(&repo2.join("file.txt"));
+    assert_eq!(content, "updated\n");
+
+    // verify that the commit was pushed to master and no feature branch was created
+    let verify_repo = temp.path().join("verify");
+    run_git(temp.path(), &["clone", &repo1_url, "verify"]);
+    let content = fs::read_to_string(verify_repo.join("file2.txt")).unwrap();
+    assert_eq!(content, "localnewfile\n");
+    let branches = Command::new("git")
+        .args(["branch", "-r"])
+        .current_dir(&verify_repo)
+        .output()
+        .expect("failed to run git branch");
+    let branches = String::from_utf8(branches.stdout).unwrap();
+    assert!(!branches.contains("feature"), "unexpected branches: {branches}");
+
+    insta::assert_snapshot!(
+    git_log(&repo2),
+    @r"
+    * commit-message
+    * update
+    * initial
+    ");
+}
+
+#[test]
+fn test_fail_without_upstream_before_stashing() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1");
+    let repo2 = temp.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+
+    // init repo1
+    run_git(&repo1, &["init"]);
+    run_git(&repo1, &["config", "user.email", "test@example.com"]);
+    run_git(&repo1, &["config", "user.name", "Test"]);
+    fs::write(repo1.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "initial"]);
+
+    // clone repo1 to repo2
+    let repo1_url = file_url(&repo1);
+    run_git(&repo2, &["clone", &repo1_url, "."]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // branch without upstream, and remote without known default branch
+    run_git(&repo2, &["checkout", "-b", "lonely"]);
+    run_git(&repo2, &["remote", "set-head", "origin", "-d"]);
+
+    // make uncommitted change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    run_sup(&repo2, &[], true);
+
+    // nothing was touched
+    let content = file_content(&repo2.join("file2.txt"));
+    assert_eq!(content, "localnewfile\n");
+    assert!(!repo2.join(".git/sup_state").exists());
+    assert!(!repo2.join(".git/refs/stash").exists());
+}
+
+fn stash_list(dir: &Path) -> String {
+    let output = Command::new("git")
+        .args(["stash", "list", "--format=%gs"])
+        .current_dir(dir)
+        .output()
+        .expect("failed to run git stash list");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_continue_applies_sup_stash_when_other_stash_was_created_meanwhile() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1");
+    let repo2 = temp.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+
+    // init repo1
+    run_git(&repo1, &["init"]);
+    run_git(&repo1, &["config", "user.email", "test@example.com"]);
+    run_git(&repo1, &["config", "user.name", "Test"]);
+    fs::write(repo1.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "initial"]);
+
+    // clone repo1 to repo2
+    let repo1_url = file_url(&repo1);
+    run_git(&repo2, &["clone", &repo1_url, "."]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // change repo1
+    fs::write(repo1.join("file.txt"), "updated\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "update"]);
+
+    // make commited conflicting change in repo2
+    fs::write(repo2.join("file.txt"), "localchange\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local change"]);
+
+    // make uncommitted non conflicting change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2, should stop on merge conflict
+    run_sup(&repo2, &[], true);
+
+    // resolve conflict and commit the resolution
+    fs::write(repo2.join("file.txt"), "resolved\n").unwrap();
+    run_git(&repo2, &["add", "file.txt"]);
+    run_git(&repo2, &["commit", "-m", "resolve conflict"]);
+
+    // stash something else on top of sup stash
+    fs::write(repo2.join("scratch.txt"), "scratch\n").unwrap();
+    run_git(&repo2, &["stash", "push", "-u", "-m", "my scratch"]);
+
+    run_sup(&repo2, &["--continue"], false);
+
+    // sup stash is applied and dropped, other stash is left alone
+    let content = file_content(&repo2.join("file2.txt"));
+    assert_eq!(content, "localnewfile\n");
+    assert!(!repo2.join("scratch.txt").exists());
+    assert_eq!(stash_list(&repo2), "On master: my scratch\n");
+}
+
+#[test]
+fn test_continue_refuses_when_sup_stash_is_missing() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1");
+    let repo2 = temp.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+
+    // init repo1
+    run_git(&repo1, &["init"]);
+    run_git(&repo1, &["config", "user.email", "test@example.com"]);
+    run_git(&repo1, &["config", "user.name", "Test"]);
+    fs::write(repo1.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "initial"]);
+
+    // clone repo1 to repo2
+    let repo1_url = file_url(&repo1);
+    run_git(&repo2, &["clone", &repo1_url, "."]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // change repo1
+    fs::write(repo1.join("file.txt"), "updated\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "update"]);
+
+    // make commited conflicting change in repo2
+    fs::write(repo2.join("file.txt"), "localchange\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local change"]);
+
+    // make uncommitted non conflicting change in repo2
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    // run sup in repo2, should stop on merge conflict
+    run_sup(&repo2, &[], true);
+
+    // resolve conflict and commit the resolution
+    fs::write(repo2.join("file.txt"), "resolved\n").unwrap();
+    run_git(&repo2, &["add", "file.txt"]);
+    run_git(&repo2, &["commit", "-m", "resolve conflict"]);
+
+    // replace sup stash with unrelated one
+    run_git(&repo2, &["stash", "drop"]);
+    fs::write(repo2.join("scratch.txt"), "scratch\n").unwrap();
+    run_git(&repo2, &["stash", "push", "-u", "-m", "my scratch"]);
+
+    // sup must not apply or drop the unrelated stash
+    run_sup(&repo2, &["--continue", "-y"], true);
+    assert!(!repo2.join("scratch.txt").exists());
+    assert_eq!(stash_list(&repo2), "On master: my scratch\n");
+}
+
+fn backup_refs(dir: &Path) -> Vec<String> {
+    let output = Command::new("git")
+        .args(["for-each-ref", "--format=%(refname)", "refs/sup/backup"])
+        .current_dir(dir)
+        .output()
+        .expect("failed to run git for-each-ref");
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+#[test]
+fn test_backup_refs_are_written_and_pruned() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1");
+    let repo2 = temp.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+
+    // init repo1
+    run_git(&repo1, &["init"]);
+    run_git(&repo1, &["config", "user.email", "test@example.com"]);
+    run_git(&repo1, &["config", "user.name", "Test"]);
+    fs::write(repo1.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "initial"]);
+
+    // clone repo1 to repo2
+    let repo1_url = file_url(&repo1);
+    run_git(&repo2, &["clone", &repo1_url, "."]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    // make staged and uncommitted changes in repo2
+    fs::write(repo2.join("staged.txt"), "staged\n").unwrap();
+    run_git(&repo2, &["add", "staged.txt"]);
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    run_sup(&repo2, &["--keep-backups", "2"], false);
+
+    let refs = backup_refs(&repo2);
+    assert_eq!(refs.len(), 3, "unexpected backup refs: {refs:?}");
+    let prefix = refs[0].rsplit_once('/').unwrap().0.to_string();
+    let show = |rev: &str| {
+        let output = Command::new("git")
+            .args(["show", rev])
+            .current_dir(&repo2)
+            .output()
+            .expect("failed to run git show");
+        String::from_utf8(output.stdout).unwrap()
+    };
+    // stash backup keeps uncommitted changes and index keeps staged ones
+    assert_eq!(show(&format!("{prefix}/stash^3:file2.txt")), "localnewfile\n");
+    assert_eq!(show(&format!("{prefix}/index:staged.txt")), "staged\n");
+
+    // only the most recent backups are kept
+    run_sup(&repo2, &["--keep-backups", "2"], false);
+    run_sup(&repo2, &["--keep-backups", "2"], false);
+    let refs = backup_refs(&repo2);
+    let mut runs: Vec<&str> = refs.iter().map(|r| r.rsplit_once('/').unwrap().0).collect();
+    runs.dedup();
+    assert_eq!(runs.len(), 2, "unexpected backup refs: {refs:?}");
+    assert!(!refs.iter().any(|r| r.starts_with(&prefix)));
+}
+
+fn rev_parse(dir: &Path, rev: &str) -> String {
+    let output = Command::new("git")
+        .args(["rev-parse", rev])
+        .current_dir(dir)
+        .output()
+        .expect("failed to run git rev-parse");
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Bare remote with initial commit and its clone to run sup in
+fn bare_remote_and_clone(temp: &Path) -> (PathBuf, PathBuf) {
+    let repo1 = temp.join("repo1_bare");
+    let repo2 = temp.join("repo2");
+    run_git(temp, &["init", "--bare", "repo1_bare"]);
+    run_git(temp, &["clone", &file_url(&repo1), "repo2"]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+    fs::write(repo2.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "initial"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+    (repo1, repo2)
+}
+
+#[test]
+fn test_undo_after_pull_keeps_local_changes() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let original_head = rev_parse(&repo2, "HEAD");
+
+    // push a new commit from another clone
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    fs::write(other.join("remote.txt"), "remote\n").unwrap();
+    run_git(&other, &["add", "."]);
+    run_git(&other, &["commit", "-m", "update"]);
+    run_git(&other, &["push", "origin", "master"]);
+
+    // make uncommitted changes and pull them with sup
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_sup(&repo2, &[], false);
+    assert!(repo2.join("remote.txt").exists());
+
+    run_sup(&repo2, &["undo"], false);
+    assert_eq!(rev_parse(&repo2, "HEAD"), original_head);
+    assert!(!repo2.join("remote.txt").exists());
+    assert_eq!(file_content(&repo2.join("file.txt")), "local change\n");
+    assert_eq!(file_content(&repo2.join("file2.txt")), "localnewfile\n");
+
+    // there is nothing to undo anymore
+    run_sup(&repo2, &["undo"], true);
+}
+
+#[test]
+fn test_undo_pushed_commit_with_force_with_lease() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let original_head = rev_parse(&repo2, "HEAD");
+
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_sup(&repo2, &["-m", "commit message"], false);
+    assert_ne!(rev_parse(&repo1, "master"), original_head);
+
+    // pushed commit is not rolled back without saying how to handle remote
+    run_sup(&repo2, &["undo"], true);
+
+    run_sup(&repo2, &["undo", "--force-with-lease"], false);
+    assert_eq!(rev_parse(&repo1, "master"), original_head);
+    assert_eq!(rev_parse(&repo2, "HEAD"), original_head);
+    assert_eq!(file_content(&repo2.join("file2.txt")), "localnewfile\n");
+    assert!(stash_list(&repo2).is_empty());
+}
+
+#[test]
+fn test_undo_refuses_force_with_lease_when_remote_was_updated() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_sup(&repo2, &["-m", "commit message"], false);
+    let pushed = rev_parse(&repo1, "master");
+
+    // someone else pushes on top of the commit
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    fs::write(other.join("remote.txt"), "remote\n").unwrap();
+    run_git(&other, &["add", "."]);
+    run_git(&other, &["commit", "-m", "update"]);
+    run_git(&other, &["push", "origin", "master"]);
+    let updated = rev_parse(&repo1, "master");
+
+    run_sup(&repo2, &["undo", "--force-with-lease"], true);
+    assert_eq!(rev_parse(&repo1, "master"), updated);
+    assert_eq!(rev_parse(&repo2, "HEAD"), pushed);
+}
+
+#[test]
+fn test_undo_pushed_commit_with_revert() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_sup(&repo2, &["-m", "commit message"], false);
+    let pushed = rev_parse(&repo2, "HEAD");
+
+    run_sup(&repo2, &["undo", "--revert"], false);
+    // revert is pushed on top of the commit
+    assert_eq!(rev_parse(&repo1, "master^"), pushed);
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&repo2, "HEAD"));
+    let output = Command::new("git")
+        .args(["ls-tree", "--name-only", "master"])
+        .current_dir(&repo1)
+        .output()
+        .expect("failed to run git ls-tree");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "file.txt\n");
+    // and the change is back in working tree
+    assert_eq!(file_content(&repo2.join("file2.txt")), "localnewfile\n");
+}
+
+fn sup_output(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("failed to run sup");
+    assert!(output.status.success(), "sup failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_status_explains_interrupted_operation() {
+    let temp = tempfile::tempdir().unwrap();
+    let repo1 = temp.path().join("repo1");
+    let repo2 = temp.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+
+    // init repo1
+    run_git(&repo1, &["init"]);
+    run_git(&repo1, &["config", "user.email", "test@example.com"]);
+    run_git(&repo1, &["config", "user.name", "Test"]);
+    fs::write(repo1.join("file.txt"), "initial\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "initial"]);
+
+    // clone repo1 to repo2
+    let repo1_url = file_url(&repo1);
+    run_git(&repo2, &["clone", &repo1_url, "."]);
+    run_git(&repo2, &["config", "user.email", "test@example.com"]);
+    run_git(&repo2, &["config", "user.name", "Test"]);
+
+    let status = sup_output(&repo2, &["status"]);
+    assert!(status.starts_with("sup state: idle\n"), "{status}");
+
+    // change repo1
+    fs::write(repo1.join("file.txt"), "updated\n").unwrap();
+    run_git(&repo1, &["add", "."]);
+    run_git(&repo1, &["commit", "-m", "update"]);
+    // make uncommitted conflicting change in repo2
+    fs::write(repo2.join("file.txt"), "localchange\n").unwrap();
+    run_sup(&repo2, &["-m", "local commit"], true);
+
+    let status = sup_output(&repo2, &["status"]);
+    assert!(status.starts_with("sup state: interrupted\n"), "{status}");
+    assert!(status.contains("  commit message: \"local commit\"\n"), "{status}");
+    assert!(status.contains("  conflicted files:\n    file.txt\n"), "{status}");
+    assert!(status.contains("run `sup continue`"), "{status}");
+
+    let status = sup_output(&repo2, &["status", "--json"]);
+    assert!(status.contains(r#""state": "interrupted""#), "{status}");
+    assert!(status.contains(r#""stash_applied": true"#), "{status}");
+    assert!(status.contains("\"conflicted_files\": [\n    \"file.txt\"\n  ]"), "{status}");
+    assert!(status.contains("\"next_commands\": [\n    \"sup continue\",\n    \"sup abort\"\n  ]"), "{status}");
+}
+
+#[test]
+fn test_commit_and_push_from_subdirectory() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let subdir = repo2.join("sub");
+    fs::create_dir(&subdir).unwrap();
+
+    // make uncommitted changes in root and subdirectory
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    fs::write(subdir.join("file3.txt"), "localnewfile\n").unwrap();
+
+    // run sup from subdirectory
+    run_sup(&subdir, &["-m", "commit message"], false);
+
+    let output = Command::new("git")
+        .args(["ls-tree", "-r", "--name-only", "master"])
+        .current_dir(&repo1)
+        .output()
+        .expect("failed to run git ls-tree");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "file.txt\nfile2.txt\nsub/file3.txt\n"
+    );
+    assert!(!repo2.join(".git/sup.lock").exists());
+    assert!(!subdir.join(".git").exists());
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_commit_and_push_from_linked_worktree() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    run_git(&repo2, &["push", "origin", "master:feature"]);
+    run_git(&repo2, &["fetch", "origin"]);
+    let worktree = temp.path().join("worktree");
+    run_git(
+        &repo2,
+        &["worktree", "add", "-b", "feature", "../worktree", "origin/feature"],
+    );
+
+    // hooks of the main repository are shared with worktrees,
+    // and are run from the root of the worktree
+    let hook_path = repo2.join(".git/hooks/pre-commit");
+    let hook_pwd = temp.path().join("hook_pwd");
+    fs::write(
+        &hook_path,
+        format!("#!/bin/sh\npwd > '{}'\n", hook_pwd.display()),
+    )
+    .unwrap();
+    let mut perms = fs::metadata(&hook_path).unwrap().permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(&hook_path, perms).unwrap();
+
+    fs::write(worktree.join("file2.txt"), "localnewfile\n").unwrap();
+    let subdir = worktree.join("sub");
+    fs::create_dir(&subdir).unwrap();
+    fs::write(subdir.join("file3.txt"), "localnewfile\n").unwrap();
+    run_sup(&subdir, &["-m", "commit message"], false);
+
+    let output = Command::new("git")
+        .args(["ls-tree", "--name-only", "feature"])
+        .current_dir(&repo1)
+        .output()
+        .expect("failed to run git ls-tree");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "file.txt\nfile2.txt\nsub\n"
+    );
+    assert_eq!(
+        fs::canonicalize(file_content(&hook_pwd).trim()).unwrap(),
+        fs::canonicalize(&worktree).unwrap()
+    );
+    // main worktree is left alone
+    assert_eq!(rev_parse(&repo2, "HEAD"), rev_parse(&repo1, "master"));
+
+    // state of the worktree is in its own git directory, which recovery hint points to
+    let head = rev_parse(&worktree, "HEAD");
+    let state_path = repo2.join(".git/worktrees/worktree/sup_state");
+    fs::write(
+        &state_path,
+        format!(
+            r#"{{"version":3,"state":{{"in_progress":{{"stash_created":false,"original_head":"{head}","message":null,"stash_oid":null}}}}}}"#
+        ),
+    )
+    .unwrap();
+    let status = sup_output(&subdir, &["status"]);
+    assert!(status.contains("worktrees/worktree/sup_state"), "{status}");
+    assert!(!status.contains(".git/sup_state"), "{status}");
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_lock_reports_holder_and_is_removed_when_stale() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+    let lock_path = repo2.join(".git/sup.lock");
+    let output = Command::new("hostname")
+        .output()
+        .expect("failed to run hostname");
+    let hostname = String::from_utf8(output.stdout).unwrap().trim().to_string();
+    let write_lock = |pid: u32| {
+        fs::write(
+            &lock_path,
+            format!(
+                r#"{{"pid":{pid},"hostname":"{hostname}","started_at":0,"command":"sup -m test"}}"#
+            ),
+        )
+        .unwrap();
+    };
+    let sup_error = || {
+        let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+            .current_dir(&repo2)
+            .output()
+            .expect("failed to run sup");
+        assert!(!output.status.success(), "sup should have failed");
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // lock held by running process (this test) is respected
+    let pid = std::process::id();
+    write_lock(pid);
+    let error = sup_error();
+    let holder = format!("Another sup process is running (process {pid} on {hostname}");
+    assert!(error.contains(&holder), "{error}");
+    assert!(error.contains("sup -m test"), "{error}");
+
+    // lock left by process that exited is reported as stale
+    let mut exited = Command::new("true").spawn().unwrap();
+    exited.wait().unwrap();
+    write_lock(exited.id());
+    let error = sup_error();
+    assert!(error.contains("which is not running anymore"), "{error}");
+    assert!(error.contains("--force-unlock"), "{error}");
+
+    run_sup(&repo2, &["--force-unlock"], false);
+    assert!(!lock_path.exists());
+}
+
+#[test]
+fn test_abort_with_state_written_in_unversioned_format() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+    let head = rev_parse(&repo2, "HEAD");
+
+    // state as written by older versions: positional fields without stash id
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_git(&repo2, &["stash", "push", "-u", "-m", "sup stash"]);
+    fs::write(
+        repo2.join(".git/sup_state"),
+        format!(r#"{{"Interrupted":[true,"{head}",null,false]}}"#),
+    )
+    .unwrap();
+
+    run_sup(&repo2, &["--abort"], false);
+    assert_eq!(file_content(&repo2.join("file2.txt")), "localnewfile\n");
+    assert!(stash_list(&repo2).is_empty());
+    assert!(!repo2.join(".git/sup_state").exists());
+}
+
+#[test]
+fn test_state_of_older_format_version_is_migrated_and_newer_one_refused() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+    let head = rev_parse(&repo2, "HEAD");
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_git(&repo2, &["stash", "push", "-u", "-m", "sup stash"]);
+    let stash = rev_parse(&repo2, "stash@{0}");
+    let state = |version: u32| {
+        format!(
+            r#"{{"version":{version},"state":{{"interrupted":{{"stash_created":true,"stash_applied":false,"original_head":"{head}","message":null,"rebasing":false,"stash_oid":"{stash}"}}}}}}"#
+        )
+    };
+
+    fs::write(repo2.join(".git/sup_state"), state(99)).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .arg("abort")
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("newer version of sup"), "{stdout}");
+    assert_eq!(stash_list(&repo2), "On master: sup stash\n");
+
+    // fields added after version 2 are missing and get their defaults
+    fs::write(repo2.join(".git/sup_state"), state(2)).unwrap();
+    let status = sup_output(&repo2, &["status"]);
+    assert!(status.contains("sup state: interrupted"), "{status}");
+    run_sup(&repo2, &["abort"], false);
+    assert_eq!(file_content(&repo2.join("file2.txt")), "localnewfile\n");
+    assert!(stash_list(&repo2).is_empty());
+    assert!(!repo2.join(".git/sup_state").exists());
+}
+
+#[test]
+fn test_run_stopped_in_progress_points_to_recovery() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+    let head = rev_parse(&repo2, "HEAD");
+    fs::write(
+        repo2.join(".git/sup_state"),
+        format!(
+            r#"{{"version":3,"state":{{"in_progress":{{"stash_created":false,"original_head":"{head}","message":null,"stash_oid":null}}}}}}"#
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("git stash list"), "{stdout}");
+    assert!(stdout.contains("sup_state"), "{stdout}");
+    assert!(!stdout.contains("sup continue"), "{stdout}");
+}
+
+#[test]
+fn test_corrupted_state_is_reported() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+    fs::write(repo2.join(".git/sup_state"), r#"{"version":2,"state":{"interr"#).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .arg("--continue")
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert!(!output.status.success(), "sup should have failed");
+    let error = String::from_utf8(output.stdout).unwrap();
+    assert!(error.contains("sup_state is corrupted: EOF"), "{error}");
+    assert!(error.contains("git stash list"), "{error}");
+}
+
+fn git_stdout(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("failed to run git");
+    assert!(output.status.success(), "git {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_staged_changes_stay_staged_after_pull() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    // stage one change and leave others unstaged
+    fs::write(repo2.join("file.txt"), "staged change\n").unwrap();
+    run_git(&repo2, &["add", "file.txt"]);
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    run_sup(&repo2, &[], false);
+
+    assert_eq!(git_stdout(&repo2, &["diff", "--cached", "--name-only"]), "file.txt\n");
+    assert_eq!(
+        git_stdout(&repo2, &["status", "--porcelain"]),
+        "M  file.txt\n?? file2.txt\n"
+    );
+}
+
+#[test]
+fn test_commit_only_staged_changes() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    fs::write(repo2.join("file.txt"), "staged change\n").unwrap();
+    run_git(&repo2, &["add", "file.txt"]);
+    fs::write(repo2.join("file.txt"), "staged change\nunstaged change\n").unwrap();
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    run_sup(&repo2, &["-m", "commit message", "--staged"], false);
+
+    assert_eq!(
+        git_stdout(&repo1, &["show", "master:file.txt"]),
+        "staged change\n"
+    );
+    assert_eq!(
+        git_stdout(&repo1, &["ls-tree", "-r", "--name-only", "master"]),
+        "file.txt\n"
+    );
+    assert_eq!(
+        file_content(&repo2.join("file.txt")),
+        "staged change\nunstaged change\n"
+    );
+    assert_eq!(
+        git_stdout(&repo2, &["status", "--porcelain"]),
+        " M file.txt\n?? file2.txt\n"
+    );
+
+    // nothing is staged anymore, so nothing is touched
+    run_sup(&repo2, &["-m", "another message", "--staged"], true);
+    assert_eq!(file_content(&repo2.join("file2.txt")), "localnewfile\n");
+    assert!(stash_list(&repo2).is_empty());
+    assert!(!repo2.join(".git/sup_state").exists());
+}
+
+#[test]
+fn test_commit_only_given_paths() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let subdir = repo2.join("sub");
+    fs::create_dir(&subdir).unwrap();
+
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    fs::write(subdir.join("file3.txt"), "localnewfile\n").unwrap();
+    fs::write(repo2.join("scratch.txt"), "notes\n").unwrap();
+
+    // paths are relative to current directory, like in git
+    run_sup(
+        &subdir,
+        &["-m", "commit message", "--", "file3.txt", "../file2.txt"],
+        false,
+    );
+
+    assert_eq!(
+        git_stdout(&repo1, &["ls-tree", "-r", "--name-only", "master"]),
+        "file.txt\nfile2.txt\nsub/file3.txt\n"
+    );
+    assert_eq!(git_stdout(&repo1, &["show", "master:file.txt"]), "initial\n");
+    assert_eq!(
+        git_stdout(&repo2, &["status", "--porcelain"]),
+        " M file.txt\n?? scratch.txt\n"
+    );
+    assert!(stash_list(&repo2).is_empty());
+
+    // paths without changes are refused before anything is done
+    run_sup(&repo2, &["-m", "commit message", "--", "file2.txt"], true);
+    assert_eq!(file_content(&repo2.join("scratch.txt")), "notes\n");
+    assert!(!repo2.join(".git/sup_state").exists());
+}
+
+#[test]
+fn test_interactive_without_changes_says_nothing_was_selected() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["-m", "commit message", "--interactive"])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("No changes selected to commit"), "{stdout}");
+    assert!(!stdout.contains("No changes to commit in"), "{stdout}");
+}
+
+#[test]
+fn test_dry_run_shows_incoming_and_outgoing_changes_without_touching_repo() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    // push a new commit from another clone
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "other@example.com"]);
+    run_git(&other, &["config", "user.name", "Other"]);
+    fs::write(other.join("file.txt"), "remote change\n").unwrap();
+    run_git(&other, &["commit", "-am", "remote update"]);
+    run_git(&other, &["push", "origin", "master"]);
+
+    // local commit and uncommitted change conflicting with remote one
+    fs::write(repo2.join("local.txt"), "local\n").unwrap();
+    run_git(&repo2, &["add", "local.txt"]);
+    run_git(&repo2, &["commit", "-m", "local update"]);
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    let head = rev_parse(&repo2, "HEAD");
+
+    let output = sup_output(&repo2, &["--dry-run"]);
+    assert!(output.contains("Incoming commits from origin/master: 1"), "{output}");
+    assert!(output.contains(" remote update (Other)"), "{output}");
+    assert!(output.contains("Local commits to push: 1"), "{output}");
+    assert!(output.contains(" local update (Test)"), "{output}");
+    assert!(
+        output.contains("Files changed on both sides:\n  file.txt\n"),
+        "{output}"
+    );
+    assert!(output.contains("Pull: merge commit would be created"), "{output}");
+    assert!(
+        output.contains("Local changes: would conflict when restored after pull:\n  file.txt\n"),
+        "{output}"
+    );
+
+    // nothing has changed except remote-tracking branch
+    assert_eq!(rev_parse(&repo2, "HEAD"), head);
+    assert_eq!(rev_parse(&repo2, "origin/master"), rev_parse(&other, "HEAD"));
+    assert_eq!(file_content(&repo2.join("file.txt")), "local change\n");
+    assert_eq!(git_stdout(&repo2, &["status", "--porcelain"]), " M file.txt\n");
+    assert!(stash_list(&repo2).is_empty());
+    assert!(!repo2.join(".git/sup_state").exists());
+    assert!(!repo2.join(".git/sup_journal").exists());
+}
+
+#[test]
+fn test_summary_of_pulled_changes_warns_about_lockfiles_migrations_and_watched_paths() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    run_git(&repo2, &["config", "--add", "sup.watch", "docker/"]);
+
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "other@example.com"]);
+    run_git(&other, &["config", "user.name", "Other"]);
+    fs::write(other.join("Cargo.lock"), "lock\n").unwrap();
+    run_git(&other, &["add", "."]);
+    run_git(&other, &["commit", "-m", "update dependencies"]);
+    fs::create_dir_all(other.join("db/migrations")).unwrap();
+    fs::write(other.join("db/migrations/001_init.sql"), "create table t;\n").unwrap();
+    fs::create_dir(other.join("docker")).unwrap();
+    fs::write(other.join("docker/compose.yml"), "services: {}\n").unwrap();
+    run_git(&other, &["add", "."]);
+    run_git(&other, &["commit", "-m", "add migration"]);
+    run_git(&other, &["push", "origin", "master"]);
+
+    let output = sup_output(&repo2, &[]);
+    assert!(output.contains("Pulled 2 commits by Other:"), "{output}");
+    assert!(output.contains(" add migration (Other)"), "{output}");
+    assert!(output.contains(" update dependencies (Other)"), "{output}");
+    assert!(output.contains("3 files changed, 3 insertions(+)"), "{output}");
+    assert!(
+        output.contains("Lockfiles changed, dependencies may need to be reinstalled: Cargo.lock"),
+        "{output}"
+    );
+    assert!(
+        output.contains("Migrations changed, they may need to be run: db/migrations/001_init.sql"),
+        "{output}"
+    );
+    assert!(
+        output.contains("Watched paths changed (sup.watch): docker/compose.yml"),
+        "{output}"
+    );
+}
+
+#[test]
+fn test_summary_of_rebase_pull_leaves_out_local_commits() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "other@example.com"]);
+    run_git(&other, &["config", "user.name", "Other"]);
+    fs::write(other.join("remote.txt"), "remote\n").unwrap();
+    run_git(&other, &["add", "."]);
+    run_git(&other, &["commit", "-m", "remote commit"]);
+    run_git(&other, &["push", "origin", "master"]);
+    fs::write(repo2.join("local.txt"), "local\nlines\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local commit"]);
+
+    let output = sup_output(&repo2, &["--rebase"]);
+    assert_eq!(rev_parse(&repo2, "HEAD~1"), rev_parse(&other, "HEAD"));
+    assert!(output.contains("Pulled 1 commit by Other:"), "{output}");
+    assert!(output.contains(" remote commit (Other)"), "{output}");
+    assert!(!output.contains("local commit"), "{output}");
+    assert!(output.contains("1 file changed, 1 insertion(+)"), "{output}");
+    assert!(!output.contains("local.txt"), "{output}");
+}
+
+/// Run sup with JSON output and parse events it wrote, returning them with exit code
+fn sup_json_events(dir: &Path, args: &[&str]) -> (Vec<serde_json::Value>, Option<i32>) {
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["--output", "json"])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("failed to run sup");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let events = stdout
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line).unwrap_or_else(|e| panic!("not JSON: {line:?}: {e}"))
+        })
+        .collect();
+    (events, output.status.code())
+}
+
+fn find_event<'a>(events: &'a [serde_json::Value], name: &str) -> &'a serde_json::Value {
+    events
+        .iter()
+        .find(|event| event["event"] == name)
+        .unwrap_or_else(|| panic!("no {name} event in {events:#?}"))
+}
+
+#[test]
+fn test_json_output_reports_steps_commit_and_push() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+
+    let (events, code) = sup_json_events(&repo2, &["-m", "commit message"]);
+    assert_eq!(code, Some(0), "{events:#?}");
+
+    let stash = find_event(&events, "stash");
+    assert_eq!(stash["created"], true);
+    assert_eq!(find_event(&events, "merge")["result"], "up_to_date");
+    assert_eq!(find_event(&events, "stash_applied")["stash_oid"], stash["stash_oid"]);
+    let commit = find_event(&events, "commit");
+    assert_eq!(commit["message"], "commit message");
+    let push = find_event(&events, "push");
+    assert_eq!(push["refname"], "refs/heads/master");
+    assert_eq!(push["new_oid"], commit["oid"]);
+    assert_eq!(commit["oid"], rev_parse(&repo1, "master").as_str());
+    assert!(events.iter().any(|event| event["event"] == "step" && event["step"] == "pushing"));
+
+    let result = events.last().unwrap();
+    assert_eq!(result["event"], "result");
+    assert_eq!(result["status"], "success");
+}
+
+#[test]
+fn test_json_output_reports_conflicts_with_exit_code() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    fs::write(other.join("file.txt"), "remote change\n").unwrap();
+    run_git(&other, &["commit", "-am", "update"]);
+    run_git(&other, &["push", "origin", "master"]);
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+
+    let (events, code) = sup_json_events(&repo2, &[]);
+    assert_eq!(code, Some(2), "{events:#?}");
+    let conflicts = find_event(&events, "conflicts");
+    assert_eq!(conflicts["stage"], "stash_apply");
+    assert_eq!(conflicts["files"], serde_json::json!(["file.txt"]));
+    let result = events.last().unwrap();
+    assert_eq!(result["event"], "result");
+    assert_eq!(result["status"], "failure");
+    assert_eq!(result["category"], "conflict");
+    assert_eq!(result["exit_code"], 2);
+
+    // status is written as a JSON line too, followed by the result
+    let (events, code) = sup_json_events(&repo2, &["status"]);
+    assert_eq!(code, Some(0));
+    assert_eq!(events.len(), 2, "{events:#?}");
+    assert_eq!(events[0]["state"], "interrupted");
+    assert_eq!(events[0]["conflicted_files"], serde_json::json!(["file.txt"]));
+    assert_eq!(events[1]["event"], "result");
+
+    // interrupted run is recorded as usual and can be aborted
+    run_sup(&repo2, &["--abort"], false);
+    assert_eq!(file_content(&repo2.join("file.txt")), "local change\n");
+}
+
+#[test]
+fn test_plain_output_when_not_run_in_terminal() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    // stdout of tests is not a terminal, so each step is logged on its own line
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    let stdout = sup_output(&repo2, &["-m", "first commit"]);
+    assert!(stdout.contains("Stashing local changes\n"), "{stdout}");
+    assert!(stdout.contains("Stashed local changes\n"), "{stdout}");
+    assert!(stdout.contains("Pushing branch 'master'\n"), "{stdout}");
+    assert!(stdout.contains("Pushed branch 'master'\n"), "{stdout}");
+    assert!(stdout.contains("Operation completed"), "{stdout}");
+
+    fs::write(repo2.join("file3.txt"), "anotherfile\n").unwrap();
+    let stdout = sup_output(&repo2, &["--quiet", "-m", "second commit"]);
+    assert_eq!(stdout, "");
+    assert_eq!(rev_parse(&repo2, "HEAD"), rev_parse(&repo2, "origin/master"));
+
+    // conflicting verbosity is reported on stderr, keeping stdout clean
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["--quiet", "--verbose", "status"])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be used together"));
+    let (events, code) = sup_json_events(&repo2, &["--quiet", "--verbose", "status"]);
+    assert_eq!(code, Some(1));
+    assert_eq!(events.len(), 1, "{events:#?}");
+    assert_eq!(events[0]["event"], "result");
+    assert_eq!(events[0]["status"], "failure");
+}
+
+#[test]
+fn test_settings_from_git_config_repo_file_environment_and_command_line() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    run_git(&repo2, &["config", "sup.pushRetries", "1"]);
+    run_git(&repo2, &["config", "sup.pullStrategy", "rebase"]);
+    fs::write(
+        repo2.join(".sup.toml"),
+        "push_retries = 2\npull_strategy = \"ff-only\"\nwatch = [\"docker/\", \"*.graphql\"]\n",
+    )
+    .unwrap();
+    run_git(&repo2, &["add", ".sup.toml"]);
+    run_git(&repo2, &["commit", "-m", "add sup settings"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+
+    let listing = sup_output(&repo2, &["config"]);
+    assert!(listing.contains("sup.pushRetries = 2 (.sup.toml)\n"), "{listing}");
+    assert!(listing.contains("sup.pullStrategy = ff-only (.sup.toml)\n"), "{listing}");
+    assert!(listing.contains("sup.keepBackups = 10 (default)\n"), "{listing}");
+    assert!(
+        listing.contains("sup.watch = docker/, *.graphql (.sup.toml)\n"),
+        "{listing}"
+    );
+
+    // environment takes precedence over the file
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["config", "--json"])
+        .env("SUP_PUSH_RETRIES", "4")
+        .env("SUP_WATCH", std::env::join_paths(["db", "api"]).unwrap())
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    let entries: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let push_retries = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["key"] == "sup.pushRetries")
+        .unwrap();
+    assert_eq!(push_retries["value"], "4");
+    assert_eq!(push_retries["source"], "env");
+    assert_eq!(push_retries["origin"], "SUP_PUSH_RETRIES");
+    let watch = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["key"] == "sup.watch")
+        .unwrap();
+    assert_eq!(watch["value"], serde_json::json!(["db", "api"]));
+    assert_eq!(watch["source"], "env");
+
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    fs::write(other.join("remote.txt"), "remote\n").unwrap();
+    run_git(&other, &["add", "."]);
+    run_git(&other, &["commit", "-m", "remote commit"]);
+    run_git(&other, &["push", "origin", "master"]);
+    fs::write(repo2.join("local.txt"), "local\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "-m", "local commit"]);
+
+    // ff-only from the file refuses to pull diverged branch
+    let status = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .current_dir(&repo2)
+        .status()
+        .expect("failed to run sup");
+    assert_eq!(status.code(), Some(3));
+
+    // while command line takes precedence over everything
+    sup_output(&repo2, &["--rebase"]);
+    assert_eq!(rev_parse(&repo2, "HEAD~1"), rev_parse(&other, "HEAD"));
+
+    fs::write(repo2.join(".sup.toml"), "pull_strategy = \"squash\"\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .arg("config")
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("Invalid value \"squash\" of sup.pullStrategy from .sup.toml"),
+        "{stdout}"
+    );
+    fs::remove_file(repo2.join(".sup.toml")).unwrap();
+
+    // boolean key without value is true in git config, while empty value is false
+    let mut git_config = fs::OpenOptions::new()
+        .append(true)
+        .open(repo2.join(".git/config"))
+        .unwrap();
+    git_config
+        .write_all(b"[sup]\n\tnoVerify\n\tautoConfirmDropStash =\n")
+        .unwrap();
+    let listing = sup_output(&repo2, &["config"]);
+    assert!(listing.contains("sup.noVerify = true (git config)\n"), "{listing}");
+    assert!(
+        listing.contains("sup.autoConfirmDropStash = false (git config)\n"),
+        "{listing}"
+    );
+}
+
+#[test]
+fn test_committed_repo_file_cannot_set_check_command_or_skip_hooks() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    fs::write(
+        repo2.join(".sup.toml"),
+        "check_command = \"touch pwned.txt\"\nno_verify = true\ngpg_sign = false\npush_retries = 2\n",
+    )
+    .unwrap();
+    run_git(&repo2, &["add", ".sup.toml"]);
+    run_git(&repo2, &["commit", "-m", "add sup settings"]);
+    run_git(&repo2, &["push", "origin", "master"]);
+
+    let listing = sup_output(&repo2, &["config"]);
+    assert!(listing.contains("sup.checkCommand is not set"), "{listing}");
+    assert!(listing.contains("sup.noVerify = false (default)\n"), "{listing}");
+    assert!(listing.contains("sup.gpgSign is not set"), "{listing}");
+    assert!(listing.contains("sup.pushRetries = 2 (.sup.toml)\n"), "{listing}");
+
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["-m", "local commit"])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Ignoring \"check_command\""));
+    assert!(!repo2.join("pwned.txt").exists());
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&repo2, "HEAD"));
+}
+
+#[test]
+fn test_subcommands_with_bare_sup_running_sync() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    fs::write(other.join("file.txt"), "remote change\n").unwrap();
+    run_git(&other, &["commit", "-am", "update"]);
+    run_git(&other, &["push", "origin", "master"]);
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+
+    run_sup(&repo2, &["-m", "local commit"], true);
+    let sup = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_sup"))
+            .args(args)
+            .current_dir(&repo2)
+            .output()
+            .expect("failed to run sup")
+    };
+    // interrupted run has to be continued or aborted first
+    let output = sup(&["sync"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("`sup continue`"), "{stdout}");
+    // options which make no sense together are rejected before anything is done
+    for args in [
+        &["--continue", "--abort"][..],
+        &["abort", "-m", "message"],
+        &["continue", "--rebase"],
+        &["sync", "--rebase", "--ff-only"],
+        &["sync", "--staged"],
+        &["-n", "status"],
+        &["--rebase", "abort"],
+    ] {
+        let output = sup(args);
+        assert_eq!(output.status.code(), Some(1), "{args:?}");
+    }
+    // command is recognized after its own switches too
+    let output = sup(&["--json", "status"]);
+    assert!(output.status.success());
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(status["state"], "interrupted");
+
+    run_sup(&repo2, &["abort"], false);
+    assert_eq!(file_content(&repo2.join("file.txt")), "local change\n");
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    fs::write(repo2.join("file.txt"), "remote change\n").unwrap();
+    run_sup(&repo2, &["sync", "-m", "local commit"], false);
+    assert_eq!(rev_parse(&repo2, "HEAD"), rev_parse(&repo1, "master"));
+}
+
+#[test]
+#[cfg(not(windows))]
+fn test_commits_are_signed_with_ssh_key_when_commit_gpgsign_is_set() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let key = temp.path().join("signing_key");
+    let Ok(status) = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key)
+        .status()
+    else {
+        println!("Skipping test, ssh-keygen is not available");
+        return;
+    };
+    assert!(status.success());
+    run_git(&repo2, &["config", "gpg.format", "ssh"]);
+    run_git(&repo2, &["config", "user.signingKey", key.to_str().unwrap()]);
+    run_git(&repo2, &["config", "commit.gpgSign", "true"]);
+
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    fs::write(other.join("remote.txt"), "remote\n").unwrap();
+    run_git(&other, &["add", "."]);
+    run_git(&other, &["commit", "-m", "remote commit"]);
+    run_git(&other, &["push", "origin", "master"]);
+    fs::write(repo2.join("local.txt"), "local\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "--no-gpg-sign", "-m", "local commit"]);
+
+    // both the merge commit made by pull and the commit of local changes are signed
+    fs::write(repo2.join("file.txt"), "signed change\n").unwrap();
+    run_sup(&repo2, &["-m", "signed commit"], false);
+    assert_eq!(rev_parse(&repo2, "HEAD"), rev_parse(&repo1, "master"));
+    for commit in ["HEAD", "HEAD~1"] {
+        let content = git_stdout(&repo2, &["cat-file", "commit", commit]);
+        assert!(
+            content.contains("-----BEGIN SSH SIGNATURE-----"),
+            "{commit} is not signed:\n{content}"
+        );
+    }
+    assert_eq!(
+        git_stdout(&repo2, &["log", "-1", "--format=%s"]),
+        "signed commit\n"
+    );
+    assert!(git_stdout(&repo2, &["reflog", "-1"]).contains("commit: signed commit"));
+
+    fs::write(repo2.join("file.txt"), "unsigned change\n").unwrap();
+    run_sup(&repo2, &["-m", "unsigned commit", "--no-gpg-sign"], false);
+    let content = git_stdout(&repo2, &["cat-file", "commit", "HEAD"]);
+    assert!(!content.contains("gpgsig"), "{content}");
+
+    // local commits replayed by rebase are signed again
+    fs::write(other.join("remote.txt"), "remote again\n").unwrap();
+    run_git(&other, &["pull", "-q", "--ff-only"]);
+    run_git(&other, &["commit", "-qam", "another remote commit"]);
+    run_git(&other, &["push", "-q", "origin", "master"]);
+    for name in ["first", "second"] {
+        fs::write(repo2.join(format!("{name}.txt")), "local\n").unwrap();
+        run_git(&repo2, &["add", "."]);
+        run_git(&repo2, &["commit", "--no-gpg-sign", "-m", name]);
+    }
+    run_sup(&repo2, &["--rebase"], false);
+    assert_eq!(rev_parse(&repo2, "HEAD~2"), rev_parse(&other, "HEAD"));
+    assert_eq!(
+        git_stdout(&repo2, &["log", "-2", "--format=%s"]),
+        "second\nfirst\n"
+    );
+    for commit in ["HEAD", "HEAD~1"] {
+        let content = git_stdout(&repo2, &["cat-file", "commit", commit]);
+        assert!(
+            content.contains("-----BEGIN SSH SIGNATURE-----"),
+            "{commit} is not signed:\n{content}"
+        );
+    }
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_hooks_run_with_git_environment_at_the_same_points_as_with_git() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let log = temp.path().join("hooks.log");
+    let hooks_dir = repo2.join(".git/hooks");
+    fs::create_dir_all(&hooks_dir).unwrap();
+    let write_hook = |name: &str, exit_code: i32| {
+        let hook_path = hooks_dir.join(name);
+        let script = format!(
+            "#!/bin/sh\necho \"{name} $* GIT_DIR=$GIT_DIR GIT_EDITOR=$GIT_EDITOR \
+            GIT_AUTHOR_NAME=$GIT_AUTHOR_NAME index=$(git ls-files | wc -l | tr -d ' ')\" >> {}\n\
+            exit {exit_code}\n",
+            log.display()
+        );
+        fs::write(&hook_path, script).unwrap();
+        let mut perms = fs::metadata(&hook_path).unwrap().permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(&hook_path, perms).unwrap();
+    };
+    for name in [
+        "pre-commit",
+        "prepare-commit-msg",
+        "commit-msg",
+        "post-commit",
+        "pre-merge-commit",
+        "post-merge",
+    ] {
+        write_hook(name, 0);
+    }
+
+    let other = temp.path().join("other");
+    run_git(temp.path(), &["clone", &file_url(&repo1), "other"]);
+    run_git(&other, &["config", "user.email", "test@example.com"]);
+    run_git(&other, &["config", "user.name", "Test"]);
+    let push_remote_commit = |name: &str| {
+        run_git(&other, &["pull", "--ff-only"]);
+        fs::write(other.join(name), "remote\n").unwrap();
+        run_git(&other, &["add", "."]);
+        run_git(&other, &["commit", "-m", name]);
+        run_git(&other, &["push", "origin", "master"]);
+    };
+    push_remote_commit("remote1.txt");
+    fs::write(repo2.join("local.txt"), "local\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "--no-verify", "-m", "local commit"]);
+    fs::remove_file(&log).unwrap();
+
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    run_sup(&repo2, &["-m", "hooked commit"], false);
+    assert_eq!(rev_parse(&repo2, "HEAD"), rev_parse(&repo1, "master"));
+    let git_dir = repo2.join(".git").canonicalize().unwrap();
+    let lines: Vec<String> = file_content(&log).lines().map(str::to_string).collect();
+    let names: Vec<&str> = lines
+        .iter()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "pre-merge-commit",
+            "post-merge",
+            "pre-commit",
+            "prepare-commit-msg",
+            "commit-msg",
+            "post-commit",
+        ],
+        "{lines:#?}"
+    );
+    for line in &lines {
+        assert!(line.contains(" GIT_EDITOR=: "), "{line}");
+        let dir = line.split("GIT_DIR=").nth(1).unwrap().split(' ').next().unwrap();
+        assert_eq!(Path::new(dir).canonicalize().unwrap(), git_dir, "{line}");
+    }
+    assert!(lines[0].contains("GIT_AUTHOR_NAME=Test"), "{}", lines[0]);
+    assert!(lines[2].contains("GIT_AUTHOR_NAME=Test"), "{}", lines[2]);
+    let commit_editmsg = git_dir.join("COMMIT_EDITMSG");
+    assert!(
+        lines[3].starts_with(&format!(
+            "prepare-commit-msg {} message ",
+            commit_editmsg.display()
+        )),
+        "{}",
+        lines[3]
+    );
+
+    // --no-verify skips commit-msg too, but not prepare-commit-msg
+    fs::remove_file(&log).unwrap();
+    fs::write(repo2.join("file.txt"), "unverified change\n").unwrap();
+    run_sup(&repo2, &["-m", "unverified commit", "--no-verify"], false);
+    let names: Vec<String> = file_content(&log)
+        .lines()
+        .map(|line| line.split(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(names, ["prepare-commit-msg", "post-commit"]);
+
+    // failing pre-merge-commit hook leaves branch and local changes where they were
+    write_hook("pre-merge-commit", 1);
+    push_remote_commit("remote2.txt");
+    fs::write(repo2.join("local2.txt"), "local\n").unwrap();
+    run_git(&repo2, &["add", "."]);
+    run_git(&repo2, &["commit", "--no-verify", "-m", "second local commit"]);
+    let head = rev_parse(&repo2, "HEAD");
+    fs::write(repo2.join("file.txt"), "kept change\n").unwrap();
+    run_sup(&repo2, &["-m", "refused commit"], true);
+    assert_eq!(rev_parse(&repo2, "HEAD"), head);
+    assert_eq!(file_content(&repo2.join("file.txt")), "kept change\n");
+    assert_eq!(git_stdout(&repo2, &["stash", "list"]), "");
+    assert!(sup_output(&repo2, &["status"]).contains("idle"));
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_pre_push_hook_gets_remote_url_and_pushed_refs_on_stdin() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let args_log = temp.path().join("args.log");
+    let input_log = temp.path().join("input.log");
+    let hook_path = repo2.join(".git/hooks/pre-push");
+    fs::create_dir_all(hook_path.parent().unwrap()).unwrap();
+    fs::write(
+        &hook_path,
+        format!(
+            "#!/bin/sh\necho \"$@\" > '{}'\ncat > '{}'\n",
+            args_log.display(),
+            input_log.display()
+        ),
+    )
+    .unwrap();
+    let mut perms = fs::metadata(&hook_path).unwrap().permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(&hook_path, perms).unwrap();
+    let old_head = rev_parse(&repo2, "HEAD");
+
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    run_sup(&repo2, &["-m", "pushed commit"], false);
+    let new_head = rev_parse(&repo2, "HEAD");
+    assert_eq!(new_head, rev_parse(&repo1, "master"));
+    assert_eq!(
+        file_content(&args_log),
+        format!("origin {}\n", file_url(&repo1))
+    );
+    assert_eq!(
+        file_content(&input_log),
+        format!("refs/heads/master {new_head} refs/heads/master {old_head}\n")
+    );
+
+    // hook does not have to read its input
+    fs::write(&hook_path, "#!/bin/sh\nexit 0\n").unwrap();
+    fs::write(repo2.join("file.txt"), "another change\n").unwrap();
+    run_sup(&repo2, &["-m", "another commit"], false);
+    assert_eq!(rev_parse(&repo2, "HEAD"), rev_parse(&repo1, "master"));
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_message_edited_by_hooks_is_cleaned_up_and_committed() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let hooks_dir = repo2.join(".git/hooks");
+    fs::create_dir_all(&hooks_dir).unwrap();
+    let write_hook = |name: &str, script: &str| {
+        let hook_path = hooks_dir.join(name);
+        fs::write(&hook_path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        let mut perms = fs::metadata(&hook_path).unwrap().permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(&hook_path, perms).unwrap();
+    };
+    write_hook(
+        "prepare-commit-msg",
+        "printf '\\n\\n; comment from template\\n' >> \"$1\"",
+    );
+    write_hook(
+        "commit-msg",
+        "printf '\\n\\nChange-Id: I1234   \\n\\n' >> \"$1\"",
+    );
+    run_git(&repo2, &["config", "commit.cleanup", "strip"]);
+    run_git(&repo2, &["config", "core.commentChar", ";"]);
+
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    run_sup(&repo2, &["-m", "\n  subject  "], false);
+    assert_eq!(rev_parse(&repo2, "HEAD"), rev_parse(&repo1, "master"));
+    assert_eq!(
+        git_stdout(&repo2, &["log", "-1", "--format=%B"]),
+        "  subject\n\nChange-Id: I1234\n\n"
+    );
+
+    // hook leaving nothing but comments aborts the commit, keeping changes in working tree
+    write_hook("commit-msg", "echo '; only a comment' > \"$1\"");
+    let head = rev_parse(&repo2, "HEAD");
+    fs::write(repo2.join("file.txt"), "another change\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["-m", "will be emptied"])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("empty commit message"), "{stdout}");
+    assert_eq!(rev_parse(&repo2, "HEAD"), head);
+    assert_eq!(file_content(&repo2.join("file.txt")), "another change\n");
+    assert!(sup_output(&repo2, &["status"]).contains("idle"));
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_failing_check_leaves_commit_unpushed_until_continue() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let pushed = rev_parse(&repo1, "master");
+    let sup = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_sup"))
+            .args(args)
+            .current_dir(&repo2)
+            .output()
+            .expect("failed to run sup")
+    };
+
+    // check runs from the root of the working tree, against the committed result
+    fs::write(repo2.join("file.txt"), "local change\n").unwrap();
+    let output = sup(&["-m", "checked commit", "--check", "grep -q fixed file.txt"]);
+    assert_eq!(output.status.code(), Some(8));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Check \"grep -q fixed file.txt\""), "{stdout}");
+    assert!(stdout.contains("Check grep -q fixed file.txt failed"), "{stdout}");
+    assert!(!stdout.contains("passed"), "{stdout}");
+    assert_eq!(rev_parse(&repo1, "master"), pushed);
+    assert_eq!(
+        git_stdout(&repo2, &["log", "-1", "--format=%s"]),
+        "checked commit\n"
+    );
+    let status = sup_output(&repo2, &["status"]);
+    assert!(status.contains("check before push: grep -q fixed file.txt"), "{status}");
+    assert!(status.contains("changes are committed, not pushed yet"), "{status}");
+    // new run is refused while check failure is not resolved
+    assert_eq!(sup(&["-m", "another"]).status.code(), Some(1));
+
+    // fix is amended into the commit, and continue checks again before pushing
+    fs::write(repo2.join("file.txt"), "fixed change\n").unwrap();
+    run_git(&repo2, &["commit", "-a", "--amend", "--no-edit"]);
+    run_sup(&repo2, &["continue"], false);
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&repo2, "HEAD"));
+    assert_eq!(git_stdout(&repo2, &["stash", "list"]), "");
+    assert!(sup_output(&repo2, &["status"]).contains("idle"));
+
+    // abort after failed check restores local changes as they were before the run
+    let head = rev_parse(&repo2, "HEAD");
+    run_git(&repo2, &["config", "sup.checkCommand", "false"]);
+    fs::write(repo2.join("file.txt"), "unchecked change\n").unwrap();
+    assert_eq!(sup(&["-m", "unchecked commit"]).status.code(), Some(8));
+    run_sup(&repo2, &["abort"], false);
+    assert_eq!(rev_parse(&repo2, "HEAD"), head);
+    assert_eq!(rev_parse(&repo1, "master"), head);
+    assert_eq!(file_content(&repo2.join("file.txt")), "unchecked change\n");
+    assert_eq!(git_stdout(&repo2, &["stash", "list"]), "");
+}
+
+#[test]
+fn test_check_does_not_see_changes_left_out_of_commit() {
+    let temp = tempfile::tempdir().unwrap();
+    let (repo1, repo2) = bare_remote_and_clone(temp.path());
+    let pushed = rev_parse(&repo1, "master");
+
+    // fix is only in working tree, so check of the committed result has to fail
+    fs::write(repo2.join("file.txt"), "fixed change\n").unwrap();
+    fs::write(repo2.join("file2.txt"), "localnewfile\n").unwrap();
+    run_git(&repo2, &["add", "file2.txt"]);
+    let check = "grep -q fixed file.txt && test ! -e fixed.txt";
+    let output = Command::new(env!("CARGO_BIN_EXE_sup"))
+        .args(["-m", "staged commit", "--staged", "--check", check])
+        .current_dir(&repo2)
+        .output()
+        .expect("failed to run sup");
+    assert_eq!(output.status.code(), Some(8));
+    assert_eq!(rev_parse(&repo1, "master"), pushed);
+    assert_eq!(
+        git_stdout(&repo2, &["show", "--name-only", "--format=", "HEAD"]),
+        "file2.txt\n"
+    );
+    // changes left out are back in working tree after the check
+    assert_eq!(file_content(&repo2.join("file.txt")), "fixed change\n");
+    assert!(!git_stdout(&repo2, &["stash", "list"]).contains("uncommitted"));
+
+    // with the fix committed too, the check passes although untracked file is around
+    run_git(&repo2, &["commit", "-a", "--amend", "--no-edit"]);
+    fs::write(repo2.join("fixed.txt"), "untracked\n").unwrap();
+    run_sup(&repo2, &["continue"], false);
+    assert_eq!(rev_parse(&repo1, "master"), rev_parse(&repo2, "HEAD"));
+    assert_eq!(file_content(&repo2.join("fixed.txt")), "untracked\n");
+    assert_eq!(git_stdout(&repo2, &["stash", "list"]), "");
+}